@@ -4,7 +4,7 @@ use log::*;
 use osmpbf::{Element, ElementReader};
 use rand::prelude::*;
 use simple_process_stats::ProcessStats;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::prelude::*;
 
 #[derive(PartialEq, Debug, Clone)]
@@ -77,6 +77,7 @@ impl Location {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::convert::TryInto;
 
     fn loc_equal(a: &Location, b: &Location) {
         let distance = a.distance_mm(b);
@@ -114,31 +115,314 @@ mod test {
             },
         );
     }
+
+    #[test]
+    fn test_file_header() {
+        let path =
+            std::env::temp_dir().join(format!("generate-places-test-{}.dat", std::process::id()));
+        let filename = path.to_str().unwrap();
+        let mut writer = FilePointWriter::new(filename, "test.osm.pbf");
+        writer.write(&Location::new(30.0, -97.0));
+        writer.write(&Location::new(40.0, -74.0));
+        // Nothing's there until the header is written and the file moved into place
+        assert!(!path.exists());
+        writer.finish().unwrap();
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&contents[0..8], MAGIC);
+        let header_len = u32::from_le_bytes(contents[12..16].try_into().unwrap()) as usize;
+        assert_eq!(header_len % 8, 0);
+        assert_eq!(contents.len(), header_len + 16);
+        assert_eq!(&contents[16..24], &2u64.to_le_bytes());
+        assert_eq!(&contents[24..28], &30.0f32.to_le_bytes());
+        assert_eq!(&contents[36..40], &(-74.0f32).to_le_bytes());
+        let checksum = contents[header_len..]
+            .iter()
+            .fold(FNV_OFFSET, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+            });
+        assert_eq!(&contents[48..56], &checksum.to_le_bytes());
+        assert_eq!(&contents[58..70], b"test.osm.pbf");
+    }
+
+    /// A boundary around the box from `min` to `max`
+    fn square(min: (f64, f64), max: (f64, f64)) -> BoundaryFilter {
+        let corners = [
+            Location::new(min.0, min.1),
+            Location::new(min.0, max.1),
+            Location::new(max.0, max.1),
+            Location::new(max.0, min.1),
+        ];
+        let edges = (0..4)
+            .map(|i| (corners[i].clone(), corners[(i + 1) % 4].clone()))
+            .collect();
+        BoundaryFilter::new(edges)
+    }
+
+    fn area(code: &str, min: (f64, f64), max: (f64, f64)) -> AdminArea {
+        let filter = square(min, max);
+        let (min, max) = filter.bounding_box().unwrap();
+        AdminArea {
+            code: code.to_string(),
+            min,
+            max,
+            filter,
+        }
+    }
+
+    #[test]
+    fn test_regions() {
+        let filter = square((0.0, 0.0), (10.0, 10.0));
+        assert!(filter.contains(&Location::new(5.0, 5.0)));
+        assert!(filter.contains(&Location::new(9.9, 0.1)));
+        assert!(!filter.contains(&Location::new(5.0, 10.5)));
+        assert!(!filter.contains(&Location::new(-1.0, 5.0)));
+        assert!(!BoundaryFilter::new(vec![]).contains(&Location::new(5.0, 5.0)));
+
+        let finder = RegionFinder {
+            countries: vec![
+                area("FR", (42.0, -5.0), (51.0, 8.0)),
+                area("DE", (47.0, 8.0), (55.0, 15.0)),
+            ],
+            regions: vec![area("FR-IDF", (48.0, 1.5), (49.5, 3.5))],
+        };
+        assert_eq!(finder.code(&Location::new(48.9, 2.4)), "FR-IDF");
+        assert_eq!(finder.code(&Location::new(43.3, 5.4)), "FR");
+        assert_eq!(finder.code(&Location::new(52.5, 13.4)), "DE");
+        assert_eq!(finder.code(&Location::new(0.0, -30.0)), "");
+
+        let path = std::env::temp_dir().join(format!(
+            "generate-places-regions-test-{}.dat",
+            std::process::id()
+        ));
+        {
+            let mut writer =
+                FilePointWriter::with_regions(path.to_str().unwrap(), "test.osm.pbf", &finder);
+            for (lat, lon) in [(52.5, 13.4), (48.9, 2.4), (43.3, 5.4), (53.5, 10.0)].iter() {
+                writer.write(&Location::new(*lat, *lon));
+            }
+            writer.finish().unwrap();
+        }
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header_len = u32::from_le_bytes(contents[12..16].try_into().unwrap()) as usize;
+        assert_eq!(contents.len(), header_len + 32);
+        // After the source and generator comes the number of regions, then each region
+        let generator_len = u16::from_le_bytes(contents[70..72].try_into().unwrap()) as usize;
+        let regions = &contents[72 + generator_len..];
+        assert_eq!(&regions[0..2], &3u16.to_le_bytes());
+        assert_eq!(&regions[2..4], &2u16.to_le_bytes());
+        assert_eq!(&regions[4..6], b"DE");
+        assert_eq!(&regions[6..14], &2u64.to_le_bytes());
+        // Each region's points are together, in the order of the regions
+        let latitudes: Vec<_> = contents[header_len..]
+            .chunks(8)
+            .map(|point| f32::from_le_bytes(point[0..4].try_into().unwrap()))
+            .collect();
+        assert_eq!(latitudes, vec![52.5, 53.5, 43.3, 48.9]);
+    }
 }
 
 trait PointWriter {
     fn write(&mut self, location: &Location);
+
+    /// Writes out anything that's been held on to, once all the points are in
+    fn finish(self) -> std::io::Result<()>;
+}
+
+/// The header in front of the points in a `.dat` file. The server's `datafile` module has
+/// the details, and must be kept in step with this.
+const MAGIC: &[u8; 8] = b"PGPLACES";
+const FORMAT_VERSION: u32 = 2;
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+struct Header {
+    count: u64,
+    min: Location,
+    max: Location,
+    created: u64,
+    checksum: u64,
+    source: String,
+    generator: String,
+    regions: Vec<RegionHeader>,
 }
 
-struct FilePointWriter {
+/// A run of points in the same region, in the order they're in the file
+struct RegionHeader {
+    /// Empty for points that aren't in any country
+    code: String,
+    count: u64,
+    min: Location,
+    max: Location,
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+    bytes.extend_from_slice(&(string.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+fn write_bounding_box(bytes: &mut Vec<u8>, min: &Location, max: &Location) {
+    for value in [min.latitude, min.longitude, max.latitude, max.longitude].iter() {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Grows the bounding box to take in the location
+fn extend_bounding_box(min: &mut Location, max: &mut Location, location: &Location) {
+    min.latitude = min.latitude.min(location.latitude);
+    min.longitude = min.longitude.min(location.longitude);
+    max.latitude = max.latitude.max(location.latitude);
+    max.longitude = max.longitude.max(location.longitude);
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        // Filled in below, once we know
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&self.count.to_le_bytes());
+        write_bounding_box(&mut bytes, &self.min, &self.max);
+        bytes.extend_from_slice(&self.created.to_le_bytes());
+        bytes.extend_from_slice(&self.checksum.to_le_bytes());
+        write_string(&mut bytes, &self.source);
+        write_string(&mut bytes, &self.generator);
+        bytes.extend_from_slice(&(self.regions.len() as u16).to_le_bytes());
+        for region in self.regions.iter() {
+            write_string(&mut bytes, &region.code);
+            bytes.extend_from_slice(&region.count.to_le_bytes());
+            write_bounding_box(&mut bytes, &region.min, &region.max);
+        }
+        // Keep the points lined up, so that they can be read straight out of the file
+        let len = (bytes.len() + 7) / 8 * 8;
+        bytes.resize(len, 0);
+        bytes[12..16].copy_from_slice(&(len as u32).to_le_bytes());
+        bytes
+    }
+}
+
+/// Writes to `<filename>.tmp`, and renames it over `filename` once it's complete, so that
+/// a server with the old file mapped doesn't see it change underneath it
+struct FilePointWriter<'a> {
     writer: std::io::BufWriter<std::fs::File>,
+    header: Header,
+
+    /// Where the file ends up
+    path: String,
+
+    /// Tags the points with their regions, if there's one
+    regions: Option<&'a RegionFinder>,
+
+    /// The points in each region, which get written out together at the end
+    pending: BTreeMap<String, Vec<Location>>,
 }
 
-impl FilePointWriter {
-    fn new(filename: &str) -> FilePointWriter {
-        let f = std::fs::File::create(filename).unwrap();
+impl<'a> FilePointWriter<'a> {
+    /// `source` is where the points came from, for the header
+    fn new(filename: &str, source: &str) -> FilePointWriter<'a> {
+        let f = std::fs::File::create(format!("{}.tmp", filename)).unwrap();
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let header = Header {
+            count: 0,
+            min: Location::new(90.0, 180.0),
+            max: Location::new(-90.0, -180.0),
+            created,
+            checksum: FNV_OFFSET,
+            source: source.to_string(),
+            generator: format!("generate_places {}", env!("CARGO_PKG_VERSION")),
+            regions: vec![],
+        };
+        let mut writer = std::io::BufWriter::new(f);
+        // Holds the space for the real header, which gets written once all the points are in
+        writer.write_all(&header.to_bytes()).unwrap();
         FilePointWriter {
-            writer: std::io::BufWriter::new(f),
+            writer,
+            header,
+            path: filename.to_string(),
+            regions: None,
+            pending: BTreeMap::new(),
         }
     }
-}
 
-impl PointWriter for FilePointWriter {
-    fn write(&mut self, location: &Location) {
+    /// Tags every point with the country (and region) it's in. The points get held on to
+    /// until the end, so that each region's points can be written out together.
+    fn with_regions(
+        filename: &str,
+        source: &str,
+        regions: &'a RegionFinder,
+    ) -> FilePointWriter<'a> {
+        let mut writer = FilePointWriter::new(filename, source);
+        writer.regions = Some(regions);
+        writer
+    }
+
+    fn write_point(&mut self, location: &Location) -> std::io::Result<()> {
         let lat = location.latitude.to_le_bytes();
         let lon = location.longitude.to_le_bytes();
-        self.writer.write_all(&lat).unwrap();
-        self.writer.write_all(&lon).unwrap();
+        self.writer.write_all(&lat)?;
+        self.writer.write_all(&lon)
+    }
+
+    /// Takes the point into account in the header
+    fn add_to_header(&mut self, location: &Location) {
+        let header = &mut self.header;
+        header.count += 1;
+        extend_bounding_box(&mut header.min, &mut header.max, location);
+        let lat = location.latitude.to_le_bytes();
+        let lon = location.longitude.to_le_bytes();
+        for byte in lat.iter().chain(lon.iter()) {
+            header.checksum = (header.checksum ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+impl<'a> PointWriter for FilePointWriter<'a> {
+    fn write(&mut self, location: &Location) {
+        match self.regions {
+            Some(regions) => self
+                .pending
+                .entry(regions.code(location))
+                .or_default()
+                .push(location.clone()),
+            None => {
+                self.write_point(location).unwrap();
+                self.add_to_header(location);
+            }
+        }
+    }
+
+    /// Fills in the header, and moves the file into place
+    fn finish(mut self) -> std::io::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        for (code, points) in pending.iter() {
+            let mut region = RegionHeader {
+                code: code.clone(),
+                count: points.len() as u64,
+                min: Location::new(90.0, 180.0),
+                max: Location::new(-90.0, -180.0),
+            };
+            for point in points.iter() {
+                self.add_to_header(point);
+                extend_bounding_box(&mut region.min, &mut region.max, point);
+            }
+            self.header.regions.push(region);
+        }
+        // Points that were held on to for their regions haven't been written yet, so they
+        // go after the header, which has grown to list the regions
+        self.writer.seek(std::io::SeekFrom::Start(0))?;
+        let header = self.header.to_bytes();
+        self.writer.write_all(&header)?;
+        for point in pending.values().flatten() {
+            self.write_point(point)?;
+        }
+        self.writer.flush()?;
+        std::fs::rename(format!("{}.tmp", self.path), &self.path)
     }
 }
 
@@ -223,10 +507,11 @@ impl KvNodeExtractor {
         }
     }
 
-    fn export(&self, mut writer: impl PointWriter) {
+    fn export(&self, mut writer: impl PointWriter) -> std::io::Result<()> {
         for node in self.nodes.iter() {
             writer.write(node);
         }
+        writer.finish()
     }
 }
 
@@ -405,10 +690,11 @@ impl RoadExtractor {
         info!("Finished exporting road points");
     }
 
-    fn export(&self, mut writer: impl PointWriter) {
+    fn export(&self, mut writer: impl PointWriter) -> std::io::Result<()> {
         for point in self.points.iter() {
             writer.write(&point);
         }
+        writer.finish()
     }
 }
 
@@ -433,10 +719,23 @@ impl<T: PointWriter> PointWriter for DebugPointTee<T> {
             .write_all(format!("{},{}\n", location.latitude, location.longitude,).as_bytes())
             .unwrap();
     }
+
+    fn finish(self) -> std::io::Result<()> {
+        self.writer.finish()
+    }
 }
 
+/// How many strips of longitude a `BoundaryFilter` divides its edges into, so that each
+/// point only has to be checked against the edges in its own strip
+const BOUNDARY_BANDS: usize = 1024;
+
 struct BoundaryFilter {
     edges: Vec<(Location, Location)>,
+
+    /// Which of the edges cross each strip of longitude
+    bands: Vec<Vec<usize>>,
+    west: f32,
+    band_width: f32,
 }
 
 fn lerp(x1: f32, x2: f32, y1: f32, y2: f32, x: f32) -> f32 {
@@ -445,9 +744,53 @@ fn lerp(x1: f32, x2: f32, y1: f32, y2: f32, x: f32) -> f32 {
 }
 
 impl BoundaryFilter {
+    fn new(edges: Vec<(Location, Location)>) -> BoundaryFilter {
+        let west = edges
+            .iter()
+            .map(|(a, b)| a.longitude.min(b.longitude))
+            .fold(180.0, f32::min);
+        let east = edges
+            .iter()
+            .map(|(a, b)| a.longitude.max(b.longitude))
+            .fold(-180.0, f32::max);
+        let band_width = ((east - west) / BOUNDARY_BANDS as f32).max(1e-6);
+        let band =
+            |longitude: f32| (((longitude - west) / band_width) as usize).min(BOUNDARY_BANDS - 1);
+        let mut bands = vec![vec![]; BOUNDARY_BANDS];
+        for (idx, (a, b)) in edges.iter().enumerate() {
+            let (from, to) = (band(a.longitude), band(b.longitude));
+            for edges in bands[from.min(to)..=from.max(to)].iter_mut() {
+                edges.push(idx);
+            }
+        }
+        BoundaryFilter {
+            edges,
+            bands,
+            west,
+            band_width,
+        }
+    }
+
+    /// The bounding box around the boundary, or None if it's empty
+    fn bounding_box(&self) -> Option<(Location, Location)> {
+        let (first, _) = self.edges.first()?;
+        let (mut min, mut max) = (first.clone(), first.clone());
+        for (a, b) in self.edges.iter() {
+            extend_bounding_box(&mut min, &mut max, a);
+            extend_bounding_box(&mut min, &mut max, b);
+        }
+        Some((min, max))
+    }
+
     fn contains(&self, location: &Location) -> bool {
+        let offset = (location.longitude - self.west) / self.band_width;
+        if self.edges.is_empty() || offset < 0.0 || offset >= BOUNDARY_BANDS as f32 {
+            // No edges are that far West or East
+            return false;
+        }
         let mut num_crossings = 0;
-        for (a, b) in self.edges.iter() {
+        for idx in self.bands[offset as usize].iter() {
+            let (a, b) = &self.edges[*idx];
             if a.longitude <= location.longitude && b.longitude <= location.longitude {
                 // Line is too far West to matter
             } else if a.longitude >= location.longitude && b.longitude >= location.longitude {
@@ -521,6 +864,55 @@ impl<T: PointWriter> PointWriter for BoundaryFilterWriter<T> {
             self.writer.write(location);
         }
     }
+
+    fn finish(self) -> std::io::Result<()> {
+        self.writer.finish()
+    }
+}
+
+/// A country or part of one, with its ISO 3166 code
+struct AdminArea {
+    code: String,
+    min: Location,
+    max: Location,
+    filter: BoundaryFilter,
+}
+
+impl AdminArea {
+    fn contains(&self, location: &Location) -> bool {
+        location.latitude >= self.min.latitude
+            && location.latitude <= self.max.latitude
+            && location.longitude >= self.min.longitude
+            && location.longitude <= self.max.longitude
+            && self.filter.contains(location)
+    }
+}
+
+/// Works out which country, and which admin_level 4 region of it, each point is in
+struct RegionFinder {
+    countries: Vec<AdminArea>,
+
+    /// Empty unless they were asked for
+    regions: Vec<AdminArea>,
+}
+
+impl RegionFinder {
+    /// The code of the region the location is in, like `FR-IDF`, or of the country if it
+    /// isn't in any of its regions, or nothing if it isn't in any country
+    fn code(&self, location: &Location) -> String {
+        let country = match self.countries.iter().find(|area| area.contains(location)) {
+            Some(country) => country,
+            None => return String::new(),
+        };
+        let prefix = format!("{}-", country.code);
+        self.regions
+            .iter()
+            .filter(|region| region.code.starts_with(&prefix))
+            .find(|region| region.contains(location))
+            .unwrap_or(country)
+            .code
+            .clone()
+    }
 }
 
 type RelId = i64;
@@ -529,6 +921,9 @@ type NodeId = i64;
 
 struct BoundaryFinder {
     boundaries: HashMap<String, RelId>,
+
+    /// The admin_level and ISO 3166 code of the countries and the biggest parts of them
+    admin_areas: HashMap<RelId, (u8, String)>,
     boundary_ways: HashMap<RelId, Vec<WayId>>,
     way_ids: HashSet<WayId>,
     ways: HashMap<WayId, Vec<NodeId>>,
@@ -540,6 +935,7 @@ impl BoundaryFinder {
     fn new() -> BoundaryFinder {
         BoundaryFinder {
             boundaries: HashMap::new(),
+            admin_areas: HashMap::new(),
             boundary_ways: HashMap::new(),
             way_ids: HashSet::new(),
             ways: HashMap::new(),
@@ -577,7 +973,7 @@ impl BoundaryFinder {
     fn filter(&self, relid: i64) -> BoundaryFilter {
         if !self.boundary_ways.contains_key(&relid) {
             error!("Could not find boundary relation ID {}!", relid);
-            return BoundaryFilter { edges: vec![] };
+            return BoundaryFilter::new(vec![]);
         }
         let mut edges = vec![];
         for way_id in self.boundary_ways.get(&relid).unwrap().iter() {
@@ -586,7 +982,7 @@ impl BoundaryFinder {
                     "Could not find way {} inside boundary relation {}",
                     way_id, relid
                 );
-                return BoundaryFilter { edges: vec![] };
+                return BoundaryFilter::new(vec![]);
             }
             for nodes in self.ways.get(way_id).unwrap().windows(2) {
                 let node_a = self.nodes.get(&nodes[0]).unwrap();
@@ -595,7 +991,41 @@ impl BoundaryFinder {
             }
         }
         edges.sort_by(|(a, _), (b, _)| a.longitude.partial_cmp(&b.longitude).unwrap());
-        BoundaryFilter { edges }
+        BoundaryFilter::new(edges)
+    }
+
+    /// Everything needed to tag points with their countries, and their admin_level 4
+    /// regions too with `admin_regions`
+    fn region_finder(&self, admin_regions: bool) -> RegionFinder {
+        let mut finder = RegionFinder {
+            countries: vec![],
+            regions: vec![],
+        };
+        // In order, so that where areas overlap, the same one wins every time
+        let mut admin_areas: Vec<_> = self.admin_areas.iter().collect();
+        admin_areas.sort_by_key(|(relid, _)| **relid);
+        for (relid, (level, code)) in admin_areas {
+            let areas = match level {
+                2 => &mut finder.countries,
+                4 if admin_regions => &mut finder.regions,
+                _ => continue,
+            };
+            let filter = self.filter(*relid);
+            if let Some((min, max)) = filter.bounding_box() {
+                areas.push(AdminArea {
+                    code: code.clone(),
+                    min,
+                    max,
+                    filter,
+                });
+            }
+        }
+        info!(
+            "Tagging points with {} countries and {} regions",
+            finder.countries.len(),
+            finder.regions.len()
+        );
+        finder
     }
 
     /// Find all "ways" which are a part of a border
@@ -617,6 +1047,17 @@ impl BoundaryFinder {
                         break;
                     }
                 }
+                let mut admin_level = None;
+                let mut code = None;
+                for (k, v) in rel.tags() {
+                    match k {
+                        "admin_level" => admin_level = v.parse::<u8>().ok(),
+                        "ISO3166-1" | "ISO3166-1:alpha2" | "ISO3166-2" => {
+                            code = Some(v.to_string())
+                        }
+                        _ => {}
+                    }
+                }
                 for (k, v) in rel.tags() {
                     if k == "boundary" && v == "administrative" {
                         let mut way_ids = vec![];
@@ -646,6 +1087,11 @@ impl BoundaryFinder {
                         }
                         self.boundaries.insert(name, rel.id());
                         self.boundary_ways.insert(rel.id(), way_ids);
+                        if let (Some(level @ 2), Some(code)) | (Some(level @ 4), Some(code)) =
+                            (admin_level, code)
+                        {
+                            self.admin_areas.insert(rel.id(), (level, code));
+                        }
                         break;
                     }
                 }
@@ -797,7 +1243,7 @@ where
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> std::io::Result<()> {
     //let reader = ElementReader::from_path("/home/lane/Downloads/planet-190812.osm.pbf").unwrap();
     //let reader = ElementReader::from_path("/home/lane/Downloads/texas-latest.osm.pbf").unwrap();
     /*let mut ways = 0_u64;
@@ -821,6 +1267,11 @@ async fn main() {
                 .takes_value(true)
                 .help("Estimated number of nodes in the file (used for progress bar)"),
         )
+        .arg(
+            Arg::with_name("admin_regions")
+                .long("admin-regions")
+                .help("Tag points with their admin_level 4 region (state, province...), as well as their country"),
+        )
         .get_matches();
 
     let planet_path = matches
@@ -927,27 +1378,29 @@ async fn main() {
 
     roads.compute_points();
 
-    mcdonalds.export(FilePointWriter::new("mcdonalds.dat"));
-    walmart.export(FilePointWriter::new("walmart.dat"));
-    roads.export(FilePointWriter::new("roads.dat"));
+    let regions = geographic_filter.region_finder(matches.is_present("admin_regions"));
+    let dat = |filename| FilePointWriter::with_regions(filename, planet_path, &regions);
+    mcdonalds.export(dat("mcdonalds.dat"))?;
+    walmart.export(dat("walmart.dat"))?;
+    roads.export(dat("roads.dat"))?;
     //geographic_filter.dump_to_file(117177, "tmp.csv");
     /*roads.export(geographic_filter.filter(
         117177,
-        DebugPointTee::new("pnts.csv", FilePointWriter::new("roads-cheswold.dat")),
+        DebugPointTee::new("pnts.csv", dat("roads-cheswold.dat")),
     ));*/
     let g = geographic_filter;
     roads.export(BoundaryFilterWriter::new(
         vec![g.filter(117177)],
-        FilePointWriter::new("roads-cheswold.dat"),
-    ));
+        dat("roads-cheswold.dat"),
+    ))?;
     roads.export(BoundaryFilterWriter::new(
         vec![g.filter(114690)],
-        FilePointWriter::new("roads-texas.dat"),
-    ));
+        dat("roads-texas.dat"),
+    ))?;
     roads.export(BoundaryFilterWriter::new(
         vec![g.filter(148838)],
-        FilePointWriter::new("roads-us.dat"),
-    ));
+        dat("roads-us.dat"),
+    ))?;
     roads.export(BoundaryFilterWriter::new(
         vec![
             g.filter(16239),   // Austria
@@ -977,6 +1430,7 @@ async fn main() {
             g.filter(52822),   // Sweden
             g.filter(90689),   // Romania
         ],
-        FilePointWriter::new("roads-eu.dat"),
-    ));
+        dat("roads-eu.dat"),
+    ))?;
+    Ok(())
 }
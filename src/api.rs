@@ -0,0 +1,492 @@
+//! JSON versions of the HTML routes, for clients other than the browser frontend.
+//! Players are kept track of with the same session cookie as the HTML routes.
+
+use rocket::http::{Cookies, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::{Request, Route, State};
+use rocket_contrib::json::Json;
+use serde_derive::Serialize;
+
+use crate::catalog::Places;
+use crate::daily::{DailyScore, Day};
+use crate::events::{EventStream, StreamSlots};
+use crate::game_logic::{Error, GameView, GuessResult, PlayerState};
+use crate::location::Location;
+use crate::panorama::PanoramaResolver;
+use crate::{
+    current_round, daily_place, error_status, final_results, nickname_or_default,
+    resolve_current_panorama, set_session, start_daily, CreateError, CreateGame, DailyError,
+    DailyPlayer, FinalResults, Games, GuardedGameAndPid, GuardedGames, JoinError, JoinGame,
+    LocationGuess, PlayerId, RoundContext, SessionError,
+};
+
+/// What went wrong, as sent to API clients
+#[derive(Serialize, Debug)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: Status,
+
+    /// Short, unchanging name for the kind of error, for clients to match on
+    error: &'static str,
+
+    /// Something to show to a human
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: Status, error: &'static str, message: &str) -> ApiError {
+        ApiError {
+            status,
+            error,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> ApiError {
+        let error = match e {
+            Error::CannotAddPlayer => "game-started",
+            Error::AlreadyStarted => "already-started",
+            Error::WaitingForPlayers => "waiting-for-players",
+            Error::UnknownPlayer(_) => "unknown-player",
+            Error::AlreadyGuessed => "already-guessed",
+            Error::WrongState(_) => "wrong-state",
+            Error::GameOver => "game-over",
+        };
+        ApiError::new(error_status(&e), error, &e.to_string())
+    }
+}
+
+impl From<JoinError> for ApiError {
+    fn from(e: JoinError) -> ApiError {
+        match e {
+            JoinError::UnknownCode => {
+                ApiError::new(Status::NotFound, "unknown-join-code", e.message())
+            }
+            JoinError::Game(e) => e.into(),
+        }
+    }
+}
+
+impl From<CreateError> for ApiError {
+    fn from(e: CreateError) -> ApiError {
+        match e {
+            CreateError::Settings(_) => {
+                ApiError::new(Status::BadRequest, "bad-settings", &e.message())
+            }
+            CreateError::TooManyGames => {
+                ApiError::new(Status::ServiceUnavailable, "too-many-games", &e.message())
+            }
+            CreateError::Locations(_) => {
+                ApiError::new(Status::BadRequest, "not-enough-locations", &e.message())
+            }
+        }
+    }
+}
+
+impl From<DailyError> for ApiError {
+    fn from(e: DailyError) -> ApiError {
+        match e {
+            DailyError::NoChallenge => {
+                ApiError::new(Status::NotFound, "no-daily-challenge", &e.message())
+            }
+            DailyError::AlreadyPlayed => {
+                ApiError::new(Status::Conflict, "already-played", &e.message())
+            }
+            DailyError::Create(e) => e.into(),
+        }
+    }
+}
+
+impl From<SessionError> for ApiError {
+    fn from(e: SessionError) -> ApiError {
+        match e {
+            SessionError::Missing => ApiError::new(
+                Status::Unauthorized,
+                "no-session",
+                "You need to create or join a game first.",
+            ),
+            SessionError::Expired => ApiError::new(
+                Status::Unauthorized,
+                "session-expired",
+                "Your game has ended.",
+            ),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let status = self.status;
+        Response::build_from(Json(self).respond_to(request)?)
+            .status(status)
+            .ok()
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Requests which need a player get the reason they don't have one, so it can be sent as JSON
+type Player = Result<GuardedGameAndPid, SessionError>;
+
+#[derive(Serialize)]
+pub struct JoinedGame {
+    player_id: PlayerId,
+    join_code: String,
+}
+
+#[derive(Serialize)]
+pub struct GameState {
+    player_id: PlayerId,
+    state: PlayerState,
+    game: GameView,
+}
+
+#[derive(Serialize)]
+pub struct DailyScores {
+    place: String,
+    day: Day,
+
+    /// Best first
+    scores: Vec<DailyScore>,
+
+    /// Every day anybody played the place, most recent first
+    days: Vec<Day>,
+}
+
+fn joined_game(db: &GuardedGames, cookies: &mut Cookies, playerid: PlayerId) -> JoinedGame {
+    let token = db.lock().unwrap().new_session(playerid);
+    set_session(cookies, token);
+    in_game(db, playerid)
+}
+
+/// The game a player who already has a session is in
+fn in_game(db: &GuardedGames, playerid: PlayerId) -> JoinedGame {
+    let mut db = db.lock().unwrap();
+    let game = db.get_game(playerid).unwrap();
+    let join_code = game.0.lock().unwrap().get_join_code().to_string();
+    JoinedGame {
+        player_id: playerid,
+        join_code,
+    }
+}
+
+fn game_state(player: GuardedGameAndPid) -> ApiResult<GameState> {
+    let game = player.0.lock().unwrap();
+    let state = game
+        .get_player(player.1)
+        .ok_or(Error::UnknownPlayer(player.1))?
+        .state;
+    Ok(Json(GameState {
+        player_id: player.1,
+        state,
+        game: game.view(),
+    }))
+}
+
+#[post("/games", format = "json", data = "<input>")]
+fn create_game(
+    db: State<GuardedGames>,
+    places: Places,
+    mut cookies: Cookies,
+    input: Json<CreateGame>,
+) -> ApiResult<JoinedGame> {
+    let settings = input.settings(&places).map_err(CreateError::from)?;
+    let game = Games::new_game(settings, places.generator())?;
+    let playerid = db
+        .lock()
+        .unwrap()
+        .create_game(game, nickname_or_default(&input.nickname))?;
+    Ok(Json(joined_game(&db, &mut cookies, playerid)))
+}
+
+#[post("/games/join", format = "json", data = "<input>")]
+fn join_game(
+    db: State<GuardedGames>,
+    mut cookies: Cookies,
+    input: Json<JoinGame>,
+) -> ApiResult<JoinedGame> {
+    let playerid = db
+        .lock()
+        .unwrap()
+        .join_game(&input.code, nickname_or_default(&input.nickname))?;
+    Ok(Json(joined_game(&db, &mut cookies, playerid)))
+}
+
+#[post("/daily/<place>", format = "json", data = "<input>")]
+fn play_daily(
+    db: State<GuardedGames>,
+    places: Places,
+    mut cookies: Cookies,
+    place: String,
+    input: Json<DailyPlayer>,
+) -> ApiResult<JoinedGame> {
+    let nickname = nickname_or_default(&input.nickname);
+    let playerid = start_daily(&db, &places, &mut cookies, &place, nickname)?;
+    Ok(Json(in_game(&db, playerid)))
+}
+
+#[get("/daily/<place>/<day>")]
+fn daily(
+    db: State<GuardedGames>,
+    places: Places,
+    place: String,
+    day: String,
+) -> ApiResult<DailyScores> {
+    daily_place(&places, &place).ok_or(DailyError::NoChallenge)?;
+    let day: Day = day
+        .parse()
+        .map_err(|e: String| ApiError::new(Status::NotFound, "bad-date", &e))?;
+    let db = db.lock().unwrap();
+    Ok(Json(DailyScores {
+        scores: db.leaderboard.scores(&place, day).to_vec(),
+        days: db.leaderboard.days(&place),
+        place,
+        day,
+    }))
+}
+
+#[get("/game")]
+fn get_game(player: Player) -> ApiResult<GameState> {
+    game_state(player?)
+}
+
+#[post("/game/start")]
+fn start_game(player: Player) -> ApiResult<GameState> {
+    let player = player?;
+    {
+        let mut game = player.0.lock().unwrap();
+        if !game.is_host(player.1) {
+            return Err(ApiError::new(
+                Status::Forbidden,
+                "not-host",
+                "Only the host can start the game.",
+            ));
+        }
+        game.start()?;
+    }
+    game_state(player)
+}
+
+#[get("/game/events")]
+fn events(slots: State<StreamSlots>, player: Player) -> Result<EventStream, ApiError> {
+    let player = player?;
+    let slot = slots.take().ok_or_else(|| {
+        ApiError::new(
+            Status::ServiceUnavailable,
+            "too-many-streams",
+            "Too many players are listening for events right now. Poll /game instead.",
+        )
+    })?;
+    let events = player.0.lock().unwrap().subscribe();
+    Ok(EventStream::new(events, slot))
+}
+
+#[get("/game/round")]
+fn get_round(resolver: State<PanoramaResolver>, player: Player) -> ApiResult<RoundContext> {
+    let player = player?;
+    resolve_current_panorama(&resolver, &player.0, player.1);
+    let game = player.0.lock().unwrap();
+    let state = game
+        .get_player(player.1)
+        .ok_or(Error::UnknownPlayer(player.1))?
+        .state;
+    if state != PlayerState::Guessing {
+        return Err(Error::WrongState(state).into());
+    }
+    Ok(Json(current_round(&game)))
+}
+
+#[post("/game/guess", format = "json", data = "<guess>")]
+fn guess(places: Places, player: Player, guess: Json<LocationGuess>) -> ApiResult<GuessResult> {
+    let player = player?;
+    let mut game = player.0.lock().unwrap();
+    if guess.too_late(&game) {
+        return Err(Error::AlreadyGuessed.into());
+    }
+    let guess = Location {
+        latitude: guess.lat,
+        longitude: guess.lon,
+    };
+    Ok(Json(game.guess(player.1, &guess, places.generator())?))
+}
+
+#[post("/game/advance")]
+fn advance(player: Player) -> ApiResult<GameState> {
+    let player = player?;
+    player.0.lock().unwrap().ready(player.1)?;
+    game_state(player)
+}
+
+#[get("/game/results")]
+fn results(player: Player) -> ApiResult<FinalResults> {
+    let player = player?;
+    let game = player.0.lock().unwrap();
+    let player = game
+        .get_player(player.1)
+        .ok_or(Error::UnknownPlayer(player.1))?;
+    Ok(Json(final_results(game.get_settings(), player)))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        create_game,
+        join_game,
+        play_daily,
+        daily,
+        get_game,
+        events,
+        start_game,
+        get_round,
+        guess,
+        advance,
+        results
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::mkrocket;
+    use rocket::http::{ContentType, Status};
+    use rocket::local::{Client, LocalResponse};
+    use serde_json::Value;
+
+    fn json(response: &mut LocalResponse) -> Value {
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn play_game() {
+        let client = Client::new(mkrocket()).unwrap();
+        let mut response = client
+            .post("/api/v1/games")
+            .header(ContentType::JSON)
+            .body(r#"{"place": "world", "nickname": "Bot", "rounds": 2, "advance": "host"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let joined = json(&mut response);
+        assert_eq!(joined["player_id"], 1);
+        assert_eq!(joined["join_code"].as_str().unwrap().len(), 5);
+
+        let mut response = client.post("/api/v1/game/start").dispatch();
+        assert_eq!(json(&mut response)["state"], "Guessing");
+
+        for round in 1..=2 {
+            let mut response = client.get("/api/v1/game/round").dispatch();
+            let current = json(&mut response);
+            assert_eq!(current["round"], round);
+            assert_eq!(current["pano_id"], "mock-pano");
+
+            let mut response = client
+                .post("/api/v1/game/guess")
+                .header(ContentType::JSON)
+                .body(r#"{"lat": 30, "lon": -90}"#)
+                .dispatch();
+            assert!(json(&mut response)["points_gained"].as_u64().is_some());
+
+            // Can't look at the round again once it's been guessed
+            let mut response = client.get("/api/v1/game/round").dispatch();
+            assert_eq!(response.status(), Status::Conflict);
+            assert_eq!(json(&mut response)["error"], "wrong-state");
+
+            let mut response = client.post("/api/v1/game/advance").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            json(&mut response);
+        }
+
+        let mut response = client.get("/api/v1/game/results").dispatch();
+        let results = json(&mut response);
+        assert_eq!(results["results"].as_array().unwrap().len(), 2);
+        assert!(results["score"].as_u64().is_some());
+
+        let mut response = client.post("/api/v1/game/advance").dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        assert_eq!(json(&mut response)["error"], "game-over");
+    }
+
+    #[test]
+    fn daily() {
+        let client = Client::new(mkrocket()).unwrap();
+        let play = || {
+            client
+                .post("/api/v1/daily/world")
+                .header(ContentType::JSON)
+                .body(r#"{"nickname": "Bot"}"#)
+                .dispatch()
+        };
+        let mut response = play();
+        assert_eq!(response.status(), Status::Ok);
+        json(&mut response);
+        let mut response = client.get("/api/v1/game").dispatch();
+        assert_eq!(json(&mut response)["state"], "Guessing");
+
+        let mut response = play();
+        assert_eq!(response.status(), Status::Conflict);
+        assert_eq!(json(&mut response)["error"], "already-played");
+
+        let mut response = client
+            .post("/api/v1/daily/us")
+            .header(ContentType::JSON)
+            .body(r#"{"nickname": "Bot"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(json(&mut response)["error"], "no-daily-challenge");
+
+        let mut response = client.get("/api/v1/daily/world/2020-11-28").dispatch();
+        let scores = json(&mut response);
+        assert_eq!(scores["day"], "2020-11-28");
+        assert_eq!(scores["scores"].as_array().unwrap().len(), 0);
+
+        let mut response = client.get("/api/v1/daily/world/2020-02-30").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(json(&mut response)["error"], "bad-date");
+    }
+
+    #[test]
+    fn errors() {
+        let client = Client::untracked(mkrocket()).unwrap();
+        let mut response = client.get("/api/v1/game").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(json(&mut response)["error"], "no-session");
+
+        let mut response = client
+            .post("/api/v1/games")
+            .header(ContentType::JSON)
+            .body(r#"{"place": "mars", "nickname": "Bot"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json(&mut response)["error"], "bad-settings");
+
+        let mut response = client
+            .post("/api/v1/games/join")
+            .header(ContentType::JSON)
+            .body(r#"{"code": "NOPE", "nickname": "Bot"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(json(&mut response)["error"], "unknown-join-code");
+
+        let mut response = client
+            .post("/api/v1/games")
+            .header(ContentType::JSON)
+            .body(r#"{"place": "world", "nickname": "Host"}"#)
+            .dispatch();
+        let code = json(&mut response)["join_code"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let mut response = client
+            .post("/api/v1/games/join")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"code": "{}", "nickname": "Guest"}}"#, code))
+            .dispatch();
+        assert_eq!(json(&mut response)["player_id"], 2);
+        let mut response = client
+            .post("/api/v1/game/start")
+            .cookies(response.cookies())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(json(&mut response)["error"], "not-host");
+    }
+}
@@ -0,0 +1,189 @@
+//! The places players can pick from, and the datasets behind them. These come from
+//! config.yaml and the `.dat` files, and get reloaded whenever either changes, without
+//! disturbing the games that are already going.
+
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::location_generator::{
+    DatafileLocationGenerator, LocationGenerator, LocationGeneratorTrait,
+};
+use crate::{read_config, PlaceSpec};
+
+/// How often to look for changes to the config and datasets
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Everything that gets swapped out together when the places are reloaded, so that a
+/// place is never offered without its dataset
+pub struct Catalog {
+    places: Vec<PlaceSpec>,
+    generator: LocationGenerator,
+}
+
+/// The catalog is swapped for a new one on reload. Anyone still using the old one keeps
+/// it until they're done.
+pub type GuardedCatalog = Arc<RwLock<Arc<Catalog>>>;
+
+impl Catalog {
+    pub fn new(places: Vec<PlaceSpec>, generator: LocationGenerator) -> Catalog {
+        Catalog { places, generator }
+    }
+
+    pub fn guarded(self) -> GuardedCatalog {
+        Arc::new(RwLock::new(Arc::new(self)))
+    }
+
+    /// Loads the datasets for the places, leaving out any that couldn't be loaded
+    pub fn load(places: Vec<PlaceSpec>, verify: bool) -> Catalog {
+        let datasets: Vec<_> = places.iter().map(|p| (p.key.as_str(), &p.source)).collect();
+        let generator = DatafileLocationGenerator::new(datasets.as_slice(), verify);
+        // Printed rather than logged, since Rocket's logging isn't set up at startup
+        for report in generator.report() {
+            eprintln!("{}", report);
+        }
+        let places = places
+            .into_iter()
+            .filter(|p| generator.has_dataset(&p.key))
+            .collect();
+        Catalog::new(places, LocationGenerator::Datafile(generator))
+    }
+
+    pub fn places(&self) -> &[PlaceSpec] {
+        &self.places
+    }
+
+    pub fn generator(&self) -> &LocationGenerator {
+        &self.generator
+    }
+}
+
+/// The catalog as it was when the request came in
+pub struct Places(Arc<Catalog>);
+
+impl Places {
+    pub fn generator(&self) -> &LocationGenerator {
+        self.0.generator()
+    }
+}
+
+impl Deref for Places {
+    type Target = [PlaceSpec];
+
+    fn deref(&self) -> &[PlaceSpec] {
+        self.0.places()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Places {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Places, ()> {
+        let catalog = request.guard::<State<GuardedCatalog>>().unwrap();
+        let current = catalog.read().unwrap().clone();
+        Outcome::Success(Places(current))
+    }
+}
+
+/// Rebuilds the catalog from the config. If nothing in the new config loads, the old
+/// catalog is kept, since that's more likely a mistake than a wish to shut everything down.
+pub fn reload(catalog: &GuardedCatalog, config_path: &str) -> Result<(), String> {
+    let config = read_config(config_path)?;
+    let new = Catalog::load(config.places, config.verify_datasets);
+    if new.places.is_empty() {
+        return Err(format!(
+            "None of the places in {} could be loaded, so nothing was changed",
+            config_path
+        ));
+    }
+    *catalog.write().unwrap() = Arc::new(new);
+    Ok(())
+}
+
+/// The config, and all the datasets it lists, whether or not they loaded last time
+fn watched_files(config_path: &str) -> Vec<String> {
+    let mut files = vec![config_path.to_string()];
+    if let Ok(config) = read_config(config_path) {
+        files.extend(
+            config
+                .places
+                .iter()
+                .filter_map(|p| p.source.filename().map(str::to_string)),
+        );
+    }
+    files
+}
+
+fn modified_times(files: &[String]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Reloads the catalog whenever the config or any of the datasets changes
+pub fn spawn_reloader(catalog: GuardedCatalog, config_path: &'static str) {
+    std::thread::spawn(move || {
+        let mut files = watched_files(config_path);
+        let mut times = modified_times(&files);
+        loop {
+            std::thread::sleep(RELOAD_CHECK_INTERVAL);
+            let new_files = watched_files(config_path);
+            let new_times = modified_times(&new_files);
+            if new_files == files && new_times == times {
+                continue;
+            }
+            log::info!("{} or its datasets changed, reloading", config_path);
+            if let Err(e) = reload(&catalog, config_path) {
+                log::error!("Could not reload the places: {}", e);
+            }
+            files = new_files;
+            times = new_times;
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reload() {
+        let dir = crate::datafile::test::TempDir::new("catalog");
+        let dataset = dir.file("japan.dat");
+        let config = dir.file("config.yaml");
+        let config_path = config.as_str();
+        std::fs::write(
+            dataset,
+            crate::datafile::test::write(&[(35.7, 139.7)], "japan.osm.pbf"),
+        )
+        .unwrap();
+        let write_config = |filename: &str| {
+            let contents = format!(
+                "authentication:\n  api_key: 1234\nplaces:\n  - key: japan\n    \
+                 human_name: Japan\n    filename: {}\n",
+                dir.file(filename)
+            );
+            std::fs::write(&config, contents).unwrap();
+        };
+
+        let catalog = Catalog::new(vec![], LocationGenerator::mock()).guarded();
+        let before = catalog.read().unwrap().clone();
+        write_config("japan.dat");
+        reload(&catalog, config_path).unwrap();
+        let after = catalog.read().unwrap().clone();
+        assert_eq!(after.places()[0].key, "japan");
+        assert!(after.generator().sample_from_dataset("japan").is_ok());
+        // Whoever had hold of the old catalog still has it
+        assert!(before.places().is_empty());
+
+        // Nothing loads, so the places stay as they were
+        write_config("missing.dat");
+        assert!(reload(&catalog, config_path).is_err());
+        std::fs::write(&config, "places: [").unwrap();
+        assert!(reload(&catalog, config_path).is_err());
+        assert_eq!(catalog.read().unwrap().places()[0].key, "japan");
+    }
+}
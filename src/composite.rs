@@ -0,0 +1,248 @@
+//! Places made out of other places, like a mix of Europe and the US, or just the parts of
+//! Europe in the Alps. They're worked out from the other places' datasets when they're
+//! loaded, so they don't need `.dat` files of their own.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::location::Location;
+
+/// Where a place's locations come from
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum DatasetSource {
+    /// A `.dat` file from generate_places
+    File { filename: String },
+
+    /// Other places' locations, put together
+    Composite { composite: Composite },
+}
+
+impl DatasetSource {
+    pub fn file(filename: &str) -> DatasetSource {
+        DatasetSource::File {
+            filename: filename.to_string(),
+        }
+    }
+
+    pub fn filename(&self) -> Option<&str> {
+        match self {
+            DatasetSource::File { filename } => Some(filename),
+            DatasetSource::Composite { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for DatasetSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatasetSource::File { filename } => write!(f, "{}", filename),
+            DatasetSource::Composite { composite } => write!(f, "{}", composite),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Composite {
+    /// Picks from each place in proportion to its weight, however many locations it has
+    Mix { parts: Vec<MixPart> },
+
+    /// Every location of every place, so bigger places come up more often
+    Union { places: Vec<String> },
+
+    /// Only the locations of a place that are inside the bounding box and polygon (either
+    /// of which can be left out). Which ones they are is kept in memory, at 4 bytes each.
+    Within {
+        place: String,
+        #[serde(default)]
+        bbox: Option<BoundingBox>,
+        #[serde(default)]
+        polygon: Option<Vec<[f64; 2]>>,
+    },
+
+    /// Picks a country (or region) first, and then a location in it, so that the ones with
+    /// the most locations don't crowd out the rest
+    Balanced {
+        place: String,
+        #[serde(default)]
+        by: RegionLevel,
+
+        /// How often each country or region comes up, compared to the others. Any that
+        /// aren't listed count as 1, and 0 leaves them out.
+        #[serde(default)]
+        weights: HashMap<String, f64>,
+    },
+}
+
+impl Composite {
+    /// The places it's made from
+    pub fn parts(&self) -> Vec<&str> {
+        match self {
+            Composite::Mix { parts } => parts.iter().map(|part| part.place.as_str()).collect(),
+            Composite::Union { places } => places.iter().map(|place| place.as_str()).collect(),
+            Composite::Within { place, .. } | Composite::Balanced { place, .. } => vec![place],
+        }
+    }
+}
+
+impl fmt::Display for Composite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Composite::Mix { parts } => {
+                let parts: Vec<_> = parts
+                    .iter()
+                    .map(|part| format!("{} x{}", part.place, part.weight))
+                    .collect();
+                write!(f, "mix of {}", parts.join(", "))
+            }
+            Composite::Union { places } => write!(f, "all of {}", places.join(", ")),
+            Composite::Within { place, .. } => write!(f, "part of {}", place),
+            Composite::Balanced { place, by, .. } => write!(f, "{} balanced by {}", place, by),
+        }
+    }
+}
+
+/// What to balance a place by
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegionLevel {
+    #[default]
+    Country,
+
+    /// The biggest parts of countries, like states or provinces, where the dataset has them
+    Region,
+}
+
+impl RegionLevel {
+    /// Which country or region an ISO 3166 code like `FR-IDF` is in, or None for points
+    /// outside any country
+    pub fn key(self, code: &str) -> Option<&str> {
+        match self {
+            _ if code.is_empty() => None,
+            RegionLevel::Country => code.split('-').next(),
+            RegionLevel::Region => Some(code),
+        }
+    }
+}
+
+impl fmt::Display for RegionLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegionLevel::Country => write!(f, "country"),
+            RegionLevel::Region => write!(f, "region"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MixPart {
+    pub place: String,
+    pub weight: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BoundingBox {
+    pub min_latitude: f64,
+    pub min_longitude: f64,
+    pub max_latitude: f64,
+    pub max_longitude: f64,
+}
+
+impl BoundingBox {
+    fn contains(&self, location: &Location) -> bool {
+        location.latitude >= self.min_latitude
+            && location.latitude <= self.max_latitude
+            && location.longitude >= self.min_longitude
+            && location.longitude <= self.max_longitude
+    }
+}
+
+/// Whether the location is inside the polygon of [latitude, longitude] corners. Edges are
+/// straight lines on the map, and don't cross the antimeridian.
+fn polygon_contains(polygon: &[[f64; 2]], location: &Location) -> bool {
+    // Count how many edges a line heading east from the location crosses
+    let (y, x) = (location.latitude, location.longitude);
+    let mut inside = false;
+    let mut previous = match polygon.last() {
+        Some(corner) => corner,
+        None => return false,
+    };
+    for corner in polygon.iter() {
+        let ([y1, x1], [y2, x2]) = (*previous, *corner);
+        if (y1 > y) != (y2 > y) && x < x1 + (y - y1) * (x2 - x1) / (y2 - y1) {
+            inside = !inside;
+        }
+        previous = corner;
+    }
+    inside
+}
+
+/// Whether a `Within` composite keeps the location
+pub fn region_contains(
+    bbox: &Option<BoundingBox>,
+    polygon: &Option<Vec<[f64; 2]>>,
+    location: &Location,
+) -> bool {
+    bbox.as_ref().map_or(true, |bbox| bbox.contains(location))
+        && polygon
+            .as_ref()
+            .map_or(true, |polygon| polygon_contains(polygon, location))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(latitude: f64, longitude: f64) -> Location {
+        Location {
+            latitude,
+            longitude,
+        }
+    }
+
+    #[test]
+    fn test_regions() {
+        let bbox = Some(BoundingBox {
+            min_latitude: 45.0,
+            min_longitude: 5.0,
+            max_latitude: 48.5,
+            max_longitude: 16.5,
+        });
+        assert!(region_contains(&bbox, &None, &at(46.0, 10.0)));
+        assert!(!region_contains(&bbox, &None, &at(40.0, 10.0)));
+
+        // A triangle, pointing north
+        let polygon = Some(vec![[0.0, 0.0], [0.0, 10.0], [10.0, 5.0]]);
+        assert!(region_contains(&None, &polygon, &at(2.0, 5.0)));
+        assert!(!region_contains(&None, &polygon, &at(9.0, 1.0)));
+        assert!(!region_contains(&None, &polygon, &at(-1.0, 5.0)));
+        assert!(!region_contains(&bbox, &polygon, &at(2.0, 5.0)));
+        assert!(region_contains(&None, &None, &at(-80.0, 170.0)));
+    }
+
+    #[test]
+    fn test_config() {
+        let source: DatasetSource = serde_yaml::from_str(
+            "composite:\n  type: mix\n  parts:\n    - place: europe\n      weight: 70\n    \
+             - place: us\n      weight: 30\n",
+        )
+        .unwrap();
+        assert_eq!(source.to_string(), "mix of europe x70, us x30");
+        let source: DatasetSource = serde_yaml::from_str("filename: roads.dat").unwrap();
+        assert_eq!(source.filename(), Some("roads.dat"));
+        let source: DatasetSource =
+            serde_yaml::from_str("composite: {type: balanced, place: world, weights: {US: 2}}")
+                .unwrap();
+        assert_eq!(source.to_string(), "world balanced by country");
+    }
+
+    #[test]
+    fn test_region_level() {
+        assert_eq!(RegionLevel::Country.key("FR-IDF"), Some("FR"));
+        assert_eq!(RegionLevel::Country.key("DE"), Some("DE"));
+        assert_eq!(RegionLevel::Region.key("FR-IDF"), Some("FR-IDF"));
+        assert_eq!(RegionLevel::Region.key(""), None);
+    }
+}
@@ -0,0 +1,299 @@
+//! The daily challenge: everybody who plays a place on the same (UTC) day gets the same
+//! locations, and goes on that day's leaderboard.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::scoring::Points;
+use crate::GameId;
+
+/// A day, counted from 1970-01-01. Written like 2020-11-28.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+#[serde(into = "String", try_from = "String")]
+pub struct Day(i64);
+
+impl Day {
+    pub fn today() -> Day {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Day::from_timestamp(secs)
+    }
+
+    /// The day the given number of seconds since 1970 falls on
+    pub fn from_timestamp(secs: u64) -> Day {
+        Day((secs / (24 * 60 * 60)) as i64)
+    }
+
+    /// Where the day's locations in the given place come from
+    pub fn seed(self, place: &str) -> u64 {
+        // FNV-1a, since the standard library's hashers are allowed to change between releases
+        format!("{}/{}", self, place)
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
+    fn from_civil(year: i64, month: i64, day: i64) -> Day {
+        // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        Day(era * 146_097 + day_of_era - 719_468)
+    }
+
+    fn to_civil(self) -> (i64, i64, i64) {
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let days = self.0 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = (shifted_month + 2) % 12 + 1;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        (year, month, day)
+    }
+}
+
+impl fmt::Display for Day {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (year, month, day) = self.to_civil();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+impl FromStr for Day {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Day, String> {
+        let bad_date = || format!("{:?} is not a date like 2020-11-28", s);
+        let parts: Vec<i64> = s
+            .split('-')
+            .map(|part| part.parse().map_err(|_| bad_date()))
+            .collect::<Result<_, _>>()?;
+        if let [year, month, day] = parts[..] {
+            let date = Day::from_civil(year, month, day);
+            // Anything out of range, like the 31st of February, comes out as some other day
+            if date.to_civil() == (year, month, day) {
+                return Ok(date);
+            }
+        }
+        Err(bad_date())
+    }
+}
+
+impl From<Day> for String {
+    fn from(day: Day) -> String {
+        day.to_string()
+    }
+}
+
+impl TryFrom<String> for Day {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Day, String> {
+        s.parse()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DailyScore {
+    pub name: String,
+    pub score: Points,
+
+    /// Which game the score came from, so it only gets counted once
+    pub game: GameId,
+}
+
+/// Who started a place's challenge on a day. Only their sessions are kept, since player
+/// ids start over from whatever games are still around when the server restarts.
+#[derive(Serialize, Deserialize, Default)]
+struct Players {
+    sessions: HashSet<String>,
+}
+
+/// Everybody's daily challenge scores, by place and then by day
+#[derive(Serialize, Deserialize, Default)]
+pub struct Leaderboard {
+    places: HashMap<String, BTreeMap<Day, Vec<DailyScore>>>,
+
+    /// Who has started each place's challenge, by place, so that they only get one go
+    /// even if they lose the cookie saying so
+    #[serde(default)]
+    started: HashMap<String, (Day, Players)>,
+}
+
+impl Leaderboard {
+    /// Returns whether the score is new
+    pub fn record(&mut self, place: &str, day: Day, score: DailyScore) -> bool {
+        let scores = self
+            .places
+            .entry(place.to_string())
+            .or_default()
+            .entry(day)
+            .or_default();
+        if scores.iter().any(|existing| existing.game == score.game) {
+            return false;
+        }
+        scores.push(score);
+        scores.sort_by(|a, b| b.score.cmp(&a.score));
+        true
+    }
+
+    /// Best first
+    pub fn scores(&self, place: &str, day: Day) -> &[DailyScore] {
+        self.places
+            .get(place)
+            .and_then(|days| days.get(&day))
+            .map_or(&[], |scores| scores.as_slice())
+    }
+
+    /// The newest game with a score, so that new games don't get mistaken for old ones
+    pub fn last_game(&self) -> GameId {
+        self.places
+            .values()
+            .flat_map(|days| days.values())
+            .flat_map(|scores| scores.iter().map(|score| score.game))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Remembers that the player started the day's challenge for the place, with the session
+    /// they had then (if any) and the one they were given for it. Only the one day is kept
+    /// track of for each place.
+    pub fn start(&mut self, place: &str, day: Day, sessions: &[&str]) {
+        let started = self
+            .started
+            .entry(place.to_string())
+            .or_insert_with(|| (day, Players::default()));
+        if started.0 != day {
+            *started = (day, Players::default());
+        }
+        started
+            .1
+            .sessions
+            .extend(sessions.iter().map(|session| session.to_string()));
+    }
+
+    /// Whether the day's challenge for the place was started with the session
+    pub fn started(&self, place: &str, day: Day, session: &str) -> bool {
+        match self.started.get(place) {
+            Some((started_day, players)) => {
+                *started_day == day && players.sessions.contains(session)
+            }
+            None => false,
+        }
+    }
+
+    /// Every day anybody played the place, most recent first
+    pub fn days(&self, place: &str) -> Vec<Day> {
+        self.places
+            .get(place)
+            .map_or(vec![], |days| days.keys().rev().copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_day() {
+        assert_eq!(Day(0).to_string(), "1970-01-01");
+        assert_eq!(Day(18594).to_string(), "2020-11-28");
+        assert_eq!("2020-11-28".parse(), Ok(Day(18594)));
+        assert_eq!(
+            "2000-02-29".parse::<Day>().unwrap().to_string(),
+            "2000-02-29"
+        );
+        assert_eq!("1969-12-31".parse(), Ok(Day(-1)));
+        assert!("2021-02-29".parse::<Day>().is_err());
+        assert!("2021-13-01".parse::<Day>().is_err());
+        assert!("yesterday".parse::<Day>().is_err());
+        assert!("2021-01".parse::<Day>().is_err());
+
+        let today = Day::today();
+        assert_eq!(today.to_string().parse(), Ok(today));
+        assert_eq!(
+            serde_json::to_string(&Day(18594)).unwrap(),
+            "\"2020-11-28\""
+        );
+    }
+
+    #[test]
+    fn test_seed() {
+        let day = Day(18594);
+        assert_eq!(day.seed("world"), day.seed("world"));
+        assert_ne!(day.seed("world"), day.seed("us"));
+        assert_ne!(day.seed("world"), Day(18595).seed("world"));
+    }
+
+    #[test]
+    fn test_leaderboard() {
+        let mut leaderboard = Leaderboard::default();
+        let score = |name: &str, score, game| DailyScore {
+            name: name.to_string(),
+            score,
+            game,
+        };
+        assert!(leaderboard.record("world", Day(10), score("Slow", 100, 1)));
+        assert!(leaderboard.record("world", Day(10), score("Fast", 900, 2)));
+        assert!(!leaderboard.record("world", Day(10), score("Fast", 900, 2)));
+        assert!(leaderboard.record("world", Day(12), score("Later", 10, 3)));
+        assert!(leaderboard.record("us", Day(11), score("Elsewhere", 10, 4)));
+
+        let names: Vec<_> = leaderboard
+            .scores("world", Day(10))
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Fast", "Slow"]);
+        assert!(leaderboard.scores("world", Day(11)).is_empty());
+        assert_eq!(leaderboard.days("world"), vec![Day(12), Day(10)]);
+        assert_eq!(leaderboard.last_game(), 4);
+
+        let saved = serde_json::to_string(&leaderboard).unwrap();
+        let loaded: Leaderboard = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded.days("us"), vec![Day(11)]);
+    }
+
+    #[test]
+    fn test_started() {
+        let mut leaderboard = Leaderboard::default();
+        leaderboard.start("world", Day(10), &["old", "new"]);
+        assert!(leaderboard.started("world", Day(10), "old"));
+        assert!(leaderboard.started("world", Day(10), "new"));
+        assert!(!leaderboard.started("world", Day(10), "other"));
+        assert!(!leaderboard.started("us", Day(10), "new"));
+
+        // A new day, a new go
+        let saved = serde_json::to_string(&leaderboard).unwrap();
+        let mut leaderboard: Leaderboard = serde_json::from_str(&saved).unwrap();
+        assert!(leaderboard.started("world", Day(10), "new"));
+        assert!(!leaderboard.started("world", Day(11), "new"));
+        leaderboard.start("world", Day(11), &["newer"]);
+        assert!(!leaderboard.started("world", Day(10), "new"));
+        assert!(leaderboard.started("world", Day(11), "newer"));
+
+        // Leaderboards from before anybody was kept track of still load, as do ones from
+        // when player ids were kept too
+        let loaded: Leaderboard = serde_json::from_str(r#"{"places": {}}"#).unwrap();
+        assert!(!loaded.started("world", Day(11), "newer"));
+        let loaded: Leaderboard = serde_json::from_str(
+            r#"{"places": {}, "started": {"world": ["1970-01-12", {"sessions": ["newer"], "players": [9]}]}}"#,
+        )
+        .unwrap();
+        assert!(loaded.started("world", Day(11), "newer"));
+    }
+}
@@ -0,0 +1,511 @@
+//! The `.dat` files that generate_places writes, and the places get their locations from.
+//!
+//! A file is a header, followed by the points: each one a little-endian f32 latitude, then
+//! longitude. The header (all little-endian) is:
+//!
+//! | Bytes | What |
+//! |-------|------|
+//! | 8 | `PGPLACES` |
+//! | 4 | Format version, currently 2 |
+//! | 4 | Length of the whole header, which is always a multiple of 8 |
+//! | 8 | Number of points |
+//! | 16 | Bounding box of the points, as f32 min latitude, min longitude, max latitude, max longitude |
+//! | 8 | When the file was made, in seconds since 1970 |
+//! | 8 | FNV-1a hash of the points |
+//! | 2 + n | Where the points came from (like the planet file), as a length and then UTF-8 |
+//! | 2 + n | What made the file, in the same way |
+//! | 2 | Number of regions (from version 2) |
+//!
+//! then for each region:
+//!
+//! | Bytes | What |
+//! |-------|------|
+//! | 2 + n | Its ISO 3166 code, like `FR` or `FR-IDF`, or nothing for points outside any country |
+//! | 8 | Number of points in it |
+//! | 16 | Bounding box of its points, in the same way as the whole file's |
+//!
+//! and then zeroes up to the length of the header. The points are in the same order as the
+//! regions, so each region's points are all together. A file with no regions doesn't say
+//! where any of its points are. Files from before there was a header are just the points.
+
+use memmap2::Mmap;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+
+use crate::daily::Day;
+use crate::location::Location;
+
+pub const MAGIC: &[u8; 8] = b"PGPLACES";
+pub const VERSION: u32 = 2;
+
+/// Each point is a little-endian f32 latitude, then longitude
+pub const POINT_SIZE: usize = 8;
+
+/// Why a dataset couldn't be used
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+
+    /// The file isn't a whole number of points long, so it's been cut short or isn't a
+    /// dataset at all
+    Truncated {
+        len: usize,
+    },
+
+    /// The header is cut short or doesn't make sense
+    BadHeader,
+
+    /// Made by a newer generate_places than this server knows about
+    UnsupportedVersion(u32),
+
+    /// The header says there are a different number of points than there are
+    WrongCount {
+        expected: u64,
+        found: usize,
+    },
+
+    /// The points aren't the ones the header was written for
+    BadChecksum,
+
+    /// There's nothing to sample from
+    Empty,
+
+    /// A composite is made from a place that isn't available
+    MissingPart(String),
+
+    /// A composite is made, however indirectly, from itself
+    Circular(String),
+
+    /// A mix needs weights above zero
+    BadWeights,
+
+    /// Balancing needs points that say which country or region they're in
+    NoRegions,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "could not read it: {}", e),
+            LoadError::Truncated { len } => write!(
+                f,
+                "it is {} bytes long, which is not a whole number of locations",
+                len
+            ),
+            LoadError::BadHeader => write!(f, "its header is damaged"),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "it is version {} of the format, and only up to {} is understood",
+                version, VERSION
+            ),
+            LoadError::WrongCount { expected, found } => write!(
+                f,
+                "it should have {} locations, but has {}",
+                expected, found
+            ),
+            LoadError::BadChecksum => write!(f, "its locations don't match its checksum"),
+            LoadError::Empty => write!(f, "it has no locations in it"),
+            LoadError::MissingPart(place) => write!(f, "{} is not available", place),
+            LoadError::Circular(place) => write!(f, "{} ends up being made from itself", place),
+            LoadError::BadWeights => write!(f, "the weights in a mix must be above zero"),
+            LoadError::NoRegions => write!(f, "its locations don't say what country they're in"),
+        }
+    }
+}
+
+/// What the header says about the points in a file
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub version: u32,
+    pub count: u64,
+    pub min: Location,
+    pub max: Location,
+
+    /// Seconds since 1970
+    pub created: u64,
+    pub checksum: u64,
+    pub source: String,
+    pub generator: String,
+
+    /// Empty for files that don't say where their points are
+    pub regions: Vec<Region>,
+}
+
+/// A country or part of one, and the run of points in it
+#[derive(Debug, Clone)]
+pub struct Region {
+    /// Empty for the points that aren't in any country
+    pub code: String,
+    pub count: u64,
+    pub min: Location,
+    pub max: Location,
+}
+
+impl Header {
+    pub fn created_day(&self) -> Day {
+        Day::from_timestamp(self.created)
+    }
+}
+
+/// FNV-1a, which is simple enough for generate_places to do exactly the same
+pub fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Reads through the header, without ever reading past the end
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        let taken = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or(LoadError::BadHeader)?;
+        self.pos += len;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn location(&mut self) -> Result<Location, LoadError> {
+        let latitude = f32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        let longitude = f32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        Ok(Location {
+            latitude: latitude as f64,
+            longitude: longitude as f64,
+        })
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| LoadError::BadHeader)
+    }
+}
+
+/// Splits a file into its header (None for files from before there were headers) and its
+/// points, checking that the points are all there. This only looks at the header, so that
+/// it's quick however big the file is; `verify` checks the points themselves.
+pub fn parse(contents: &[u8]) -> Result<(Option<Header>, &[u8]), LoadError> {
+    if !contents.starts_with(MAGIC) {
+        // No real latitude starts with the magic, so this is an old file
+        if contents.len() % POINT_SIZE != 0 {
+            return Err(LoadError::Truncated {
+                len: contents.len(),
+            });
+        }
+        return Ok((None, contents));
+    }
+
+    let mut cursor = Cursor {
+        bytes: contents,
+        pos: MAGIC.len(),
+    };
+    let version = cursor.u32()?;
+    if version > VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let header_len = cursor.u32()? as usize;
+    let count = cursor.u64()?;
+    let min = cursor.location()?;
+    let max = cursor.location()?;
+    let created = cursor.u64()?;
+    let checksum = cursor.u64()?;
+    let source = cursor.string()?;
+    let generator = cursor.string()?;
+    let mut regions = vec![];
+    if version >= 2 {
+        for _ in 0..cursor.u16()? {
+            regions.push(Region {
+                code: cursor.string()?,
+                count: cursor.u64()?,
+                min: cursor.location()?,
+                max: cursor.location()?,
+            });
+        }
+    }
+    if !regions.is_empty() && regions.iter().map(|region| region.count).sum::<u64>() != count {
+        return Err(LoadError::BadHeader);
+    }
+    if header_len < cursor.pos || header_len > contents.len() {
+        return Err(LoadError::BadHeader);
+    }
+
+    let points = &contents[header_len..];
+    if points.len() % POINT_SIZE != 0 || points.len() / POINT_SIZE != count as usize {
+        return Err(LoadError::WrongCount {
+            expected: count,
+            found: points.len() / POINT_SIZE,
+        });
+    }
+    let header = Header {
+        version,
+        count,
+        min,
+        max,
+        created,
+        checksum,
+        source,
+        generator,
+        regions,
+    };
+    Ok((Some(header), points))
+}
+
+/// Checks the points are the ones the header was written for
+pub fn verify(header: &Header, points: &[u8]) -> Result<(), LoadError> {
+    if checksum(points) != header.checksum {
+        return Err(LoadError::BadChecksum);
+    }
+    Ok(())
+}
+
+/// The points in a file, read straight out of it as they're needed rather than all loaded
+/// up front
+pub struct Dataset {
+    map: Mmap,
+
+    /// Where the points start
+    offset: usize,
+    len: usize,
+}
+
+impl Dataset {
+    /// With `verify`, reads the whole file to check it, rather than just the header
+    pub fn open(filename: &str, verify: bool) -> Result<(Option<Header>, Dataset), LoadError> {
+        let file = File::open(filename).map_err(LoadError::Io)?;
+        // Some platforms won't map an empty file
+        if file.metadata().map_err(LoadError::Io)?.len() == 0 {
+            return Err(LoadError::Empty);
+        }
+        // Safe as long as nobody writes to the file while it's mapped. generate_places
+        // writes new datasets next to the old ones and renames them into place, which
+        // leaves the mapped file as it was, so copy new datasets in the same way.
+        let map = unsafe { Mmap::map(&file) }.map_err(LoadError::Io)?;
+        Dataset::from_map(map, verify)
+    }
+
+    fn from_map(map: Mmap, verify: bool) -> Result<(Option<Header>, Dataset), LoadError> {
+        let (header, points) = parse(&map)?;
+        if let (Some(header), true) = (&header, verify) {
+            self::verify(header, points)?;
+        }
+        let dataset = Dataset {
+            offset: map.len() - points.len(),
+            len: points.len() / POINT_SIZE,
+            map,
+        };
+        if dataset.len == 0 {
+            return Err(LoadError::Empty);
+        }
+        Ok((header, dataset))
+    }
+
+    /// Copies the points into memory of their own, for datasets that aren't files
+    pub fn from_bytes(contents: &[u8]) -> Result<(Option<Header>, Dataset), LoadError> {
+        if contents.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        let mut map = memmap2::MmapMut::map_anon(contents.len()).map_err(LoadError::Io)?;
+        map.copy_from_slice(contents);
+        Dataset::from_map(map.make_read_only().map_err(LoadError::Io)?, true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Panics if `idx` is past the end, like indexing a slice
+    pub fn get(&self, idx: usize) -> Location {
+        assert!(idx < self.len, "point {} of {}", idx, self.len);
+        let start = self.offset + idx * POINT_SIZE;
+        let point = &self.map[start..start + POINT_SIZE];
+        Location {
+            latitude: f32::from_le_bytes(point[0..4].try_into().unwrap()) as f64,
+            longitude: f32::from_le_bytes(point[4..8].try_into().unwrap()) as f64,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Location> + '_ {
+        (0..self.len).map(move |idx| self.get(idx))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn write_string(bytes: &mut Vec<u8>, string: &str) {
+        bytes.extend_from_slice(&(string.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(string.as_bytes());
+    }
+
+    /// A directory for a test's files, which is deleted when it's dropped
+    pub struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        /// Named after the test, this process and how many others it's made, so that
+        /// neither test runs nor tests sharing a name trip over each other
+        pub fn new(name: &str) -> TempDir {
+            static MADE: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "placeguessr-{}-test-{}-{}",
+                name,
+                std::process::id(),
+                MADE.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        pub fn path(&self) -> &std::path::Path {
+            &self.0
+        }
+
+        /// The path of a file in the directory
+        pub fn file(&self, name: &str) -> String {
+            self.0.join(name).to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes a file the way generate_places does
+    pub fn write(points: &[(f32, f32)], source: &str) -> Vec<u8> {
+        write_regions(&[], points, source)
+    }
+
+    /// Writes a file with the points in regions, each a code and how many of the points
+    /// are in it, in order
+    pub fn write_regions(regions: &[(&str, u64)], points: &[(f32, f32)], source: &str) -> Vec<u8> {
+        let mut body = vec![];
+        for (lat, lon) in points.iter() {
+            body.extend_from_slice(&lat.to_le_bytes());
+            body.extend_from_slice(&lon.to_le_bytes());
+        }
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        // Filled in once the length is known
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&(points.len() as u64).to_le_bytes());
+        for value in [-10.0f32, -20.0, 10.0, 20.0].iter() {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header.extend_from_slice(&1_606_521_600u64.to_le_bytes());
+        header.extend_from_slice(&checksum(&body).to_le_bytes());
+        write_string(&mut header, source);
+        write_string(&mut header, "test");
+        header.extend_from_slice(&(regions.len() as u16).to_le_bytes());
+        for (code, count) in regions.iter() {
+            write_string(&mut header, code);
+            header.extend_from_slice(&count.to_le_bytes());
+            for value in [-10.0f32, -20.0, 10.0, 20.0].iter() {
+                header.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        let header_len = (header.len() + 7) / 8 * 8;
+        header[12..16].copy_from_slice(&(header_len as u32).to_le_bytes());
+        header.resize(header_len, 0);
+        header.extend(body);
+        header
+    }
+
+    #[test]
+    fn test_parse() {
+        let file = write(&[(1.0, 2.0), (3.0, 4.0)], "planet.osm.pbf");
+        let (header, points) = parse(&file).unwrap();
+        let header = header.unwrap();
+        assert_eq!(header.count, 2);
+        assert_eq!(header.source, "planet.osm.pbf");
+        assert_eq!(header.generator, "test");
+        assert_eq!(header.created_day().to_string(), "2020-11-28");
+        assert_eq!(header.max.longitude, 20.0);
+        assert_eq!(points.len(), 16);
+        assert_eq!(&points[8..12], &3.0f32.to_le_bytes());
+
+        // Headerless files are all points
+        let (header, points) = parse(&file[file.len() - 16..]).unwrap();
+        assert!(header.is_none());
+        assert_eq!(points.len(), 16);
+
+        // However the file is cut short, it's an error rather than a panic
+        for len in 1..file.len() {
+            assert!(parse(&file[..len]).is_err(), "{} bytes", len);
+        }
+
+        let mut damaged = file.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        let (header, points) = parse(&damaged).unwrap();
+        assert!(matches!(
+            verify(&header.unwrap(), points),
+            Err(LoadError::BadChecksum)
+        ));
+        assert!(matches!(
+            Dataset::from_bytes(&damaged),
+            Err(LoadError::BadChecksum)
+        ));
+        let mut newer = file;
+        newer[8] = 3;
+        assert!(matches!(
+            parse(&newer),
+            Err(LoadError::UnsupportedVersion(3))
+        ));
+    }
+
+    #[test]
+    fn test_regions() {
+        let points = [(48.9, 2.4), (43.3, 5.4), (52.5, 13.4)];
+        let file = write_regions(&[("FR-IDF", 1), ("FR-PAC", 1), ("DE", 1)], &points, "x");
+        let (header, points) = parse(&file).unwrap();
+        let regions = header.unwrap().regions;
+        let codes: Vec<_> = regions.iter().map(|r| r.code.as_str()).collect();
+        assert_eq!(codes, vec!["FR-IDF", "FR-PAC", "DE"]);
+        assert_eq!(regions[2].count, 1);
+        assert_eq!(points.len(), 24);
+
+        // The regions have to account for every point
+        let file = write_regions(&[("FR", 1), ("DE", 1)], &[(1.0, 2.0)], "x");
+        assert!(matches!(parse(&file), Err(LoadError::BadHeader)));
+
+        // Version 1 files don't have regions
+        let mut old = write(&[(1.0, 2.0)], "x");
+        old[8] = 1;
+        assert!(parse(&old).unwrap().0.unwrap().regions.is_empty());
+    }
+
+    #[test]
+    fn test_dataset() {
+        let (header, dataset) =
+            Dataset::from_bytes(&write(&[(1.0, 2.0), (3.0, 4.0)], "planet.osm.pbf")).unwrap();
+        assert!(header.is_some());
+        assert_eq!(dataset.len(), 2);
+        assert_eq!(dataset.get(1).latitude, 3.0);
+        let longitudes: Vec<_> = dataset.iter().map(|l| l.longitude).collect();
+        assert_eq!(longitudes, vec![2.0, 4.0]);
+
+        let (header, dataset) = Dataset::from_bytes(&5.0f32.to_le_bytes().repeat(2)).unwrap();
+        assert!(header.is_none());
+        assert_eq!(dataset.get(0).longitude, 5.0);
+        assert!(matches!(Dataset::from_bytes(&[]), Err(LoadError::Empty)));
+    }
+}
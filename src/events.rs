@@ -0,0 +1,180 @@
+use rocket::http::ContentType;
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::game_logic::GameEvent;
+
+/// How often to send something when nothing is happening, so that we notice when
+/// the player goes away (and so that proxies don't give up on the connection)
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// How long a stream stays open before it's ended, and the browser reconnects, so that a
+/// page that's been left open doesn't hold on to a worker forever
+const MAX_STREAM_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// Each open stream ties up one of Rocket's workers, so only so many are allowed at once.
+/// Past that, the pages go back to polling, which leaves the rest of the workers free for
+/// everything else (including whatever the streams are waiting on).
+#[derive(Clone)]
+pub struct StreamSlots {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl StreamSlots {
+    pub fn new(max: usize) -> StreamSlots {
+        StreamSlots {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// None if there are already as many streams open as there can be
+    pub fn take(&self) -> Option<StreamSlot> {
+        if self.open.fetch_add(1, Ordering::SeqCst) >= self.max {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(StreamSlot {
+            open: self.open.clone(),
+        })
+    }
+}
+
+/// Held by a stream for as long as it's open
+pub struct StreamSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Streams a game's events to a player as Server-Sent Events, until the game goes away.
+///
+/// Rocket flushes whatever has been read so far when the reader says it would block,
+/// which is how each event gets sent as soon as it happens.
+pub struct EventStream {
+    events: Receiver<GameEvent>,
+    buffer: Vec<u8>,
+    position: usize,
+    flushed: bool,
+    opened: Instant,
+    _slot: StreamSlot,
+}
+
+impl EventStream {
+    pub fn new(events: Receiver<GameEvent>, slot: StreamSlot) -> EventStream {
+        EventStream {
+            events,
+            // Gets the headers out, so the browser knows it's connected
+            buffer: b": connected\n\n".to_vec(),
+            position: 0,
+            flushed: false,
+            opened: Instant::now(),
+            _slot: slot,
+        }
+    }
+}
+
+fn format_event(event: &GameEvent) -> Vec<u8> {
+    let data = serde_json::to_value(event).unwrap();
+    format!(
+        "event: {}\ndata: {}\n\n",
+        data["type"].as_str().unwrap(),
+        data
+    )
+    .into_bytes()
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            if !self.flushed {
+                self.flushed = true;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let age = self.opened.elapsed();
+            if age >= MAX_STREAM_AGE {
+                return Ok(0);
+            }
+            self.buffer = match self
+                .events
+                .recv_timeout(KEEPALIVE.min(MAX_STREAM_AGE - age))
+            {
+                Ok(event) => format_event(&event),
+                Err(RecvTimeoutError::Timeout) => b": keepalive\n\n".to_vec(),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Ok(0);
+                }
+            };
+            self.position = 0;
+            self.flushed = false;
+        }
+        let remaining = &self.buffer[self.position..];
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl<'r> Responder<'r> for EventStream {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("text", "event-stream"))
+            .raw_header("Cache-Control", "no-cache")
+            .chunked_body(self, 4096)
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    /// Everything up until the stream wants to flush
+    fn read_chunk(stream: &mut EventStream) -> io::Result<String> {
+        let mut chunk = vec![];
+        let mut buf = [0; 8];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => chunk.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(String::from_utf8(chunk).unwrap())
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let (sender, receiver) = channel();
+        let slots = StreamSlots::new(1);
+        let mut stream = EventStream::new(receiver, slots.take().unwrap());
+        assert!(slots.take().is_none());
+        assert_eq!(read_chunk(&mut stream).unwrap(), ": connected\n\n");
+
+        sender.send(GameEvent::PlayerGuessed { player: 3 }).unwrap();
+        assert_eq!(
+            read_chunk(&mut stream).unwrap(),
+            "event: player-guessed\ndata: {\"player\":3,\"type\":\"player-guessed\"}\n\n"
+        );
+
+        drop(sender);
+        assert_eq!(read_chunk(&mut stream).unwrap(), "");
+
+        // Once the stream is gone, there's room for another
+        drop(stream);
+        assert!(slots.take().is_some());
+    }
+}
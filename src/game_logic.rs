@@ -1,47 +1,83 @@
+use crate::composite::RegionLevel;
 use crate::location::Location;
-use crate::location_generator::{LocationGenerator, LocationGeneratorTrait};
+use crate::location_generator::{LocationGenerator, LocationGeneratorTrait, SampleError};
+use crate::panorama::Panorama;
+use crate::scoring::{Points, ScoreBreakdown, ScoredGuess, Scorer, ScorerTrait};
+use crate::settings::{AdvancePolicy, GameSettings};
 use crate::DistanceKm;
 use crate::PlayerId;
-use serde_derive::Serialize;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
-//use std::time::Instant;
-
-type Points = usize;
-
-/// We want approximately the following place numbers
-/// Maximum score: 10,000pts, <1km
-/// Same city: 5,000pts, <20km
-/// Same state (Texas): 2,500pts, <200km
-/// Same US-sized country (US, China, Australia, Mexico, Europe): 1,000pts, <2,000km
-/// Same planet: 10pts, <10,000km
-fn distance_to_points(distance: DistanceKm) -> usize {
-    if distance < 1.0 {
-        10_000
-    } else if distance > 20_000.0 {
-        10
-    } else {
-        (10_000.0 - 3174.471323 * distance.ln().sqrt()) as usize
-    }
-}
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
+    /// The game has already started, so nobody else can join
     CannotAddPlayer,
+
+    /// The game can only be started once
+    AlreadyStarted,
+
+    /// Some players are still guessing the current location
+    WaitingForPlayers,
+
+    /// There's no such player in the game
+    UnknownPlayer(PlayerId),
+
+    /// The player has already guessed the current location
+    AlreadyGuessed,
+
+    /// The player isn't in a state where they can do that
+    WrongState(PlayerState),
+
     GameOver,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::CannotAddPlayer => write!(f, "That game has already started."),
+            Error::AlreadyStarted => write!(f, "The game has already started."),
+            Error::WaitingForPlayers => write!(f, "Not everyone has guessed yet."),
+            Error::UnknownPlayer(_) => write!(f, "You aren't in this game."),
+            Error::AlreadyGuessed => write!(f, "You've already guessed this location."),
+            Error::WrongState(PlayerState::Joined) => {
+                write!(f, "The game hasn't started yet.")
+            }
+            Error::WrongState(PlayerState::Guessing) => {
+                write!(f, "You haven't guessed this location yet.")
+            }
+            Error::WrongState(_) => write!(f, "You can't do that right now."),
+            Error::GameOver => write!(f, "The game is over."),
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GuessResult {
-    guess: Location,
-    actual: Location,
-    distance: DistanceKm,
-    points_gained: Points,
-    new_points: Points,
+    /// None if the player ran out of time before guessing
+    pub guess: Option<Location>,
+    pub actual: Location,
+    pub distance: Option<DistanceKm>,
+    pub points_gained: Points,
+    pub new_points: Points,
+
+    /// Where the points came from
+    #[serde(default)]
+    pub breakdown: ScoreBreakdown,
+
+    /// Whether the guess was in the right country, for places whose datasets say
+    #[serde(default)]
+    pub right_country: Option<bool>,
 }
 
-#[derive(Serialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub enum PlayerState {
     /// Player is in the game, waiting for other players to join
     Joined,
@@ -56,27 +92,118 @@ pub enum PlayerState {
     FinalResults,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Player {
     pub name: String,
     pub points: usize,
     pub state: PlayerState,
-    pub guesses: Vec<Location>,
+    pub results: Vec<GuessResult>,
+
+    /// Whether the player is done looking at the results, and wants to move on
+    #[serde(default)]
+    pub ready: bool,
+}
+
+/// What the clients are allowed to know about the game
+#[derive(Serialize, Debug)]
+pub struct GameView {
+    player_names: HashMap<PlayerId, String>,
+    player_states: HashMap<PlayerId, PlayerState>,
+    player_points: HashMap<PlayerId, Points>,
+    player_ready: HashMap<PlayerId, bool>,
+    host: Option<PlayerId>,
+    advance: AdvancePolicy,
+    everyone_guessed: bool,
+    locations_remaining: usize,
+
+    /// Only revealed once nobody is guessing the current location anymore
+    current_location: Option<Location>,
 }
 
-#[derive(Serialize)]
+/// Something that happened in a game, which the players should hear about right away
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum GameEvent {
+    PlayerJoined {
+        player: PlayerId,
+        name: String,
+    },
+    GameStarted,
+    PlayerGuessed {
+        player: PlayerId,
+    },
+    PlayerReady {
+        player: PlayerId,
+    },
+
+    /// Everyone who hadn't guessed yet ran out of time
+    RoundEnded,
+
+    /// Everybody is on to the given location
+    RoundAdvanced {
+        round: usize,
+    },
+    GameFinished,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Game {
     players: HashMap<PlayerId, Player>,
+    host: Option<PlayerId>,
+    join_code: String,
+
+    /// Session tokens of the players, so that they can pick the game back up after a restart
+    #[serde(default)]
+    sessions: HashMap<String, PlayerId>,
+
+    started: bool,
+    settings: GameSettings,
+
+    /// How guesses are turned into points, picked from the settings when the game is created.
+    /// Games saved before there were scorers get the default one here, until `resume` picks
+    /// the right one.
+    #[serde(default)]
+    scorer: Scorer,
+
+    #[serde(skip)]
+    state_timeout: Option<Instant>,
+
+    /// When the current location was first shown
+    #[serde(skip, default = "Instant::now")]
+    round_started: Instant,
+
     locations: Vec<Location>,
+    panoramas: Vec<Option<Panorama>>,
     locations_remaining: usize,
-    dataset: String,
+
+    /// Whether the game has changed since it was last saved
+    #[serde(skip)]
+    dirty: bool,
+
+    /// When a player last had anything to do with the game
+    #[serde(skip, default = "Instant::now")]
+    last_active: Instant,
+
+    /// Everyone listening for events
+    #[serde(skip)]
+    subscribers: Vec<Sender<GameEvent>>,
 }
 
 impl Game {
-    pub fn new(num_locations: usize, generator: &LocationGenerator, dataset: &str) -> Game {
-        let locations: Vec<_> = (0..num_locations)
-            .map(|_| generator.sample_from_dataset(dataset))
-            .collect();
+    pub fn new(
+        mut settings: GameSettings,
+        generator: &LocationGenerator,
+    ) -> std::result::Result<Game, SampleError> {
+        let seed = *settings
+            .seed
+            .get_or_insert_with(|| rand::thread_rng().gen());
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let locations = generator.sample_spaced(
+            &settings.dataset,
+            settings.num_rounds,
+            settings.min_spacing_km,
+            &mut rng,
+        )?;
         // These are some test-case locations that have proven tricky
         /*let locations = vec![
             Location {
@@ -109,27 +236,129 @@ impl Game {
             },
         ];*/
         let num_locations = locations.len();
-        Game {
+        Ok(Game {
             players: HashMap::new(),
-            //state_timeout: None,
-            locations: locations,
+            host: None,
+            join_code: String::new(),
+            sessions: HashMap::new(),
+            started: false,
+            scorer: Scorer::from(settings.scoring),
+            settings,
+            state_timeout: None,
+            round_started: Instant::now(),
+            locations,
+            panoramas: vec![None; num_locations],
             locations_remaining: num_locations,
-            dataset: dataset.to_string(),
-        }
+            dirty: true,
+            last_active: Instant::now(),
+            subscribers: vec![],
+        })
+    }
+
+    /// Returns whether the game needs saving, and assumes that it's about to be saved
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.dirty, false)
+    }
+
+    /// Hear about everything that happens in the game from now on. The receiver is
+    /// disconnected once the game goes away.
+    pub fn subscribe(&mut self) -> Receiver<GameEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    fn emit(&mut self, event: GameEvent) {
+        // Anyone who has stopped listening gets forgotten about
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Note that a player is still around
+    pub fn touch(&mut self, now: Instant) {
+        self.last_active = now;
+    }
+
+    /// How long it's been since any player did anything with the game
+    pub fn idle_time(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_active)
     }
 
+    /// Picks a saved game back up. Timers don't survive being saved and loaded, so the
+    /// current one starts over, and the scorer is picked from the settings again.
+    pub fn resume(&mut self) {
+        self.scorer = Scorer::from(self.settings.scoring);
+        let now = Instant::now();
+        self.state_timeout = if !self.started || self.is_finished() {
+            None
+        } else if self.everyone_guessed() {
+            self.settings.results_duration().map(|limit| now + limit)
+        } else {
+            self.settings.round_duration().map(|limit| now + limit)
+        };
+    }
+
+    /// Add a player to the lobby. The first player to join becomes the host.
     pub fn add_player(&mut self, id: PlayerId, nickname: &str) -> Result<()> {
-        let guesses: Vec<Location> = vec![];
+        if self.started {
+            return Err(Error::CannotAddPlayer);
+        }
         let player = Player {
             name: nickname.to_string(),
             points: 0,
             state: PlayerState::Joined,
-            guesses,
+            results: vec![],
+            ready: false,
         };
         self.players.insert(id, player);
+        if self.host.is_none() {
+            self.host = Some(id);
+        }
+        self.dirty = true;
+        self.emit(GameEvent::PlayerJoined {
+            player: id,
+            name: nickname.to_string(),
+        });
         Ok(())
     }
 
+    pub fn get_settings(&self) -> &GameSettings {
+        &self.settings
+    }
+
+    /// Which location (counting from 1) everybody is on
+    pub fn get_round(&self) -> usize {
+        (self.locations.len() - self.locations_remaining + 1).min(self.locations.len())
+    }
+
+    /// How long until the game moves on by itself, if it will
+    pub fn get_time_remaining(&self, now: Instant) -> Option<Duration> {
+        self.state_timeout
+            .map(|timeout| timeout.saturating_duration_since(now))
+    }
+
+    pub fn set_join_code(&mut self, join_code: &str) {
+        self.join_code = join_code.to_string();
+        self.dirty = true;
+    }
+
+    pub fn get_join_code(&self) -> &str {
+        &self.join_code
+    }
+
+    pub fn add_session(&mut self, token: &str, id: PlayerId) {
+        self.sessions.insert(token.to_string(), id);
+        self.dirty = true;
+    }
+
+    pub fn get_sessions(&self) -> &HashMap<String, PlayerId> {
+        &self.sessions
+    }
+
+    pub fn is_host(&self, id: PlayerId) -> bool {
+        self.host == Some(id)
+    }
+
     pub fn get_location(&self, idx: usize) -> Location {
         self.locations[idx].clone()
     }
@@ -138,6 +367,20 @@ impl Game {
         self.locations[self.locations.len() - self.locations_remaining].clone()
     }
 
+    pub fn get_current_panorama(&self) -> Option<&Panorama> {
+        self.panoramas
+            .get(self.locations.len() - self.locations_remaining)
+            .and_then(|p| p.as_ref())
+    }
+
+    /// The panorama's location becomes the answer, since that's what the players are looking at
+    pub fn set_current_panorama(&mut self, panorama: Panorama) {
+        let idx = self.locations.len() - self.locations_remaining;
+        self.locations[idx] = panorama.location.clone();
+        self.panoramas[idx] = Some(panorama);
+        self.dirty = true;
+    }
+
     pub fn get_locations_remaining(&self) -> usize {
         self.locations_remaining
     }
@@ -150,6 +393,48 @@ impl Game {
         self.players.get(&id)
     }
 
+    pub fn view(&self) -> GameView {
+        let anyone_guessing = self
+            .players
+            .values()
+            .any(|player| player.state == PlayerState::Guessing);
+        let current_location = if self.started && !anyone_guessing {
+            // Once the game is over, the "current" location is the last one
+            self.locations
+                .get(self.locations.len() - self.locations_remaining.max(1))
+                .cloned()
+        } else {
+            None
+        };
+        GameView {
+            player_names: self
+                .players
+                .iter()
+                .map(|(id, player)| (*id, player.name.clone()))
+                .collect(),
+            player_states: self
+                .players
+                .iter()
+                .map(|(id, player)| (*id, player.state))
+                .collect(),
+            player_points: self
+                .players
+                .iter()
+                .map(|(id, player)| (*id, player.points))
+                .collect(),
+            player_ready: self
+                .players
+                .iter()
+                .map(|(id, player)| (*id, player.ready))
+                .collect(),
+            host: self.host,
+            advance: self.settings.advance,
+            everyone_guessed: self.everyone_guessed(),
+            locations_remaining: self.locations_remaining,
+            current_location,
+        }
+    }
+
     pub fn is_finished(&self) -> bool {
         self.locations_remaining == 0
     }
@@ -162,57 +447,236 @@ impl Game {
             .fold(true, |acc, x| acc && x)
     }
 
-    pub fn start(&mut self) {
+    /// Everyone in the lobby starts guessing the first location
+    pub fn start(&mut self) -> Result<()> {
+        if self.started {
+            return Err(Error::AlreadyStarted);
+        }
+        self.started = true;
+        self.dirty = true;
         for (_, player) in self.players.iter_mut() {
             player.state = PlayerState::Guessing;
         }
+        self.round_started = Instant::now();
+        self.state_timeout = self
+            .settings
+            .round_duration()
+            .map(|limit| self.round_started + limit);
+        self.emit(GameEvent::GameStarted);
+        Ok(())
     }
 
-    /// Move everybody to the next guess
+    /// Enforce the time limits. Players who haven't guessed by the end of the round
+    /// get no points, and once the results have been up long enough everybody moves on,
+    /// unless it's up to the host.
+    pub fn tick(&mut self, now: Instant) {
+        let timeout = match self.state_timeout {
+            Some(timeout) if timeout <= now => timeout,
+            _ => {
+                return;
+            }
+        };
+        self.dirty = true;
+        if !self.is_finished() && !self.everyone_guessed() {
+            self.end_round();
+            // Count the results screen from when the round ran out, not from when we noticed
+            self.state_timeout = self
+                .settings
+                .results_duration()
+                .map(|limit| timeout + limit);
+            self.advance_if_ready();
+        } else if self.everyone_guessed() && self.settings.advance != AdvancePolicy::Host {
+            // advance_guess can only fail once the game is over, which we checked above
+            let _ = self.advance_guess();
+        } else {
+            self.state_timeout = None;
+        }
+    }
+
+    /// Anyone who hasn't guessed yet is out of luck, and gets no points
+    fn end_round(&mut self) {
+        let actual = self.get_current_location();
+        if !self.everyone_guessed() {
+            self.emit(GameEvent::RoundEnded);
+        }
+        for (_, player) in self.players.iter_mut() {
+            if player.state == PlayerState::Guessing {
+                player.state = PlayerState::GuessResults;
+                player.results.push(GuessResult {
+                    guess: None,
+                    actual: actual.clone(),
+                    distance: None,
+                    points_gained: 0,
+                    new_points: player.points,
+                    breakdown: ScoreBreakdown::default(),
+                    right_country: None,
+                });
+            }
+        }
+    }
+
+    /// Move on to the next location, if the game's advance policy says it's time
+    fn advance_if_ready(&mut self) {
+        let num_ready = self.players.values().filter(|player| player.ready).count();
+        let host_ready = self
+            .host
+            .and_then(|host| self.players.get(&host))
+            .map_or(false, |host| host.ready);
+        let advance = match self.settings.advance {
+            // Any time limit on the results is left to tick
+            AdvancePolicy::Automatic => {
+                self.everyone_guessed() && self.settings.results_time_limit.is_none()
+            }
+            AdvancePolicy::Host => self.everyone_guessed() && host_ready,
+            AdvancePolicy::Vote => num_ready * 2 > self.players.len(),
+            AdvancePolicy::Timer => false,
+        };
+        if advance {
+            self.end_round();
+            // Can't fail: the game's still going, and everyone has guessed now
+            let _ = self.advance_guess();
+        }
+    }
+
+    /// The player is done with the results, and wants to move on. Returns whether the
+    /// game has moved on to the next location.
+    pub fn ready(&mut self, player_id: PlayerId) -> Result<bool> {
+        if self.locations_remaining == 0 {
+            return Err(Error::GameOver);
+        }
+        let everyone_guessed = self.everyone_guessed();
+        let is_host = self.is_host(player_id);
+        let player = self
+            .players
+            .get_mut(&player_id)
+            .ok_or(Error::UnknownPlayer(player_id))?;
+        if player.state != PlayerState::GuessResults {
+            return Err(Error::WrongState(player.state));
+        }
+        // The host doesn't get to leave people behind
+        if self.settings.advance == AdvancePolicy::Host && is_host && !everyone_guessed {
+            return Err(Error::WaitingForPlayers);
+        }
+        player.ready = true;
+        self.dirty = true;
+        self.emit(GameEvent::PlayerReady { player: player_id });
+        let round = self.get_round();
+        self.advance_if_ready();
+        Ok(self.get_round() != round || self.is_finished())
+    }
+
+    /// Move everybody to the next guess, once they've all guessed the current one
     pub fn advance_guess(&mut self) -> Result<()> {
         if self.locations_remaining == 0 {
             return Err(Error::GameOver);
         }
+        if !self.started {
+            return Err(Error::WrongState(PlayerState::Joined));
+        }
+        if !self.everyone_guessed() {
+            return Err(Error::WaitingForPlayers);
+        }
         self.locations_remaining -= 1;
+        self.dirty = true;
         for (_, player) in self.players.iter_mut() {
             player.state = if self.locations_remaining == 0 {
                 PlayerState::FinalResults
             } else {
                 PlayerState::Guessing
             };
+            player.ready = false;
+        }
+        if self.locations_remaining == 0 {
+            self.emit(GameEvent::GameFinished);
+        } else {
+            self.emit(GameEvent::RoundAdvanced {
+                round: self.get_round(),
+            });
         }
+        self.round_started = Instant::now();
+        self.state_timeout = if self.locations_remaining == 0 {
+            None
+        } else {
+            self.settings
+                .round_duration()
+                .map(|limit| self.round_started + limit)
+        };
         Ok(())
     }
 
-    /// Record a guess for the given player
-    pub fn guess(&mut self, player_id: PlayerId, guess: &Location) -> Result<GuessResult> {
+    /// Whether the guess is in the same country as the location, going by the place's
+    /// dataset. None if it doesn't know what country either of them is in.
+    fn right_country(
+        &self,
+        actual: &Location,
+        guess: &Location,
+        generator: &LocationGenerator,
+    ) -> Option<bool> {
+        let country = |location| {
+            let region = generator.region_at(&self.settings.dataset, location)?;
+            RegionLevel::Country.key(&region).map(str::to_string)
+        };
+        Some(country(actual)? == country(guess)?)
+    }
+
+    /// Record a guess for the given player. The generator says what country the guess
+    /// is in.
+    pub fn guess(
+        &mut self,
+        player_id: PlayerId,
+        guess: &Location,
+        generator: &LocationGenerator,
+    ) -> Result<GuessResult> {
         if self.locations_remaining == 0 {
             return Err(Error::GameOver);
         }
+        match self.get_player(player_id).map(|player| player.state) {
+            Some(PlayerState::Guessing) => {}
+            Some(PlayerState::GuessResults) => return Err(Error::AlreadyGuessed),
+            Some(state) => return Err(Error::WrongState(state)),
+            None => return Err(Error::UnknownPlayer(player_id)),
+        }
         let actual = self.get_current_location(); //&self.locations[self.locations.len() - self.locations_remaining];
-        let distance = actual.distance_to(&guess);
+        let distance = actual.distance_to(guess);
+        // Only places whose dataset knows its countries can say
+        let right_country = if generator.has_regions(&self.settings.dataset) {
+            self.right_country(&actual, guess, generator)
+        } else {
+            None
+        };
         //game.players_guessed += 1;
-        let points = distance_to_points(distance);
-        let mut player = self
-            .players
-            .get_mut(&player_id)
-            .expect("Could not locate player!");
+        let mut scored = ScoredGuess::new(
+            distance,
+            self.settings.scoring_extent,
+            self.round_started.elapsed(),
+            self.settings.round_duration(),
+        );
+        scored.right_country = right_country;
+        let breakdown = self.scorer.score(&scored);
+        let points = breakdown.total();
+        let player = self.players.get_mut(&player_id).unwrap();
         player.points += points;
         player.state = PlayerState::GuessResults;
-        player.guesses.push(guess.clone());
-        //*.player_points.get_mut(&playerid).unwrap() += points;
-        //*game.player_states.get_mut(&playerid).unwrap() = PlayerState::GuessResults;
-        /*game.player_guesses
-        .get_mut(&playerid)
-        .unwrap()
-        .push(guess.clone());*/
-        Ok(GuessResult {
-            guess: guess.clone(),
-            actual: actual.clone(),
-            distance: distance,
+        self.dirty = true;
+        let result = GuessResult {
+            guess: Some(guess.clone()),
+            actual,
+            distance: Some(distance),
             points_gained: points,
             new_points: player.points,
-        })
+            breakdown,
+            right_country,
+        };
+        player.results.push(result.clone());
+        self.emit(GameEvent::PlayerGuessed { player: player_id });
+        if self.everyone_guessed() {
+            self.state_timeout = self
+                .settings
+                .results_duration()
+                .map(|limit| Instant::now() + limit);
+        }
+        self.advance_if_ready();
+        Ok(result)
     }
 }
 
@@ -220,10 +684,25 @@ impl Game {
 mod test {
     use super::*;
     use crate::location_generator::LocationGenerator;
+    use crate::settings::ScoringCurve;
+
+    fn settings(num_rounds: usize) -> GameSettings {
+        let mut settings = GameSettings::new("world");
+        settings.num_rounds = num_rounds;
+        settings
+    }
+
+    /// Waits on the results until the host moves on, rather than moving on as soon as
+    /// everyone has guessed
+    fn hosted(num_rounds: usize) -> GameSettings {
+        let mut settings = settings(num_rounds);
+        settings.advance = AdvancePolicy::Host;
+        settings
+    }
 
     #[test]
     fn test_add_player() {
-        let mut game = Game::new(5, &LocationGenerator::mock(), "world");
+        let mut game = Game::new(settings(5), &LocationGenerator::mock()).unwrap();
         assert_eq!(game.add_player(1234, "MyNickname"), Ok(()));
         assert_eq!(game.get_locations_remaining(), 5);
         assert_eq!(game.get_player_ids(), vec![1234]);
@@ -231,20 +710,457 @@ mod test {
         assert_eq!(game.get_player(1234).unwrap().points, 0);
     }
 
+    #[test]
+    fn test_seed() {
+        let game = Game::new(settings(5), &LocationGenerator::mock()).unwrap();
+        assert!(game.get_settings().seed.is_some());
+
+        let mut seeded = settings(5);
+        seeded.seed = Some(1234);
+        let game = Game::new(seeded, &LocationGenerator::mock()).unwrap();
+        assert_eq!(game.get_settings().seed, Some(1234));
+
+        // The same seed picks the same locations, from a dataset with plenty to pick from
+        use crate::composite::DatasetSource;
+        use crate::location_generator::DatafileLocationGenerator;
+        let dir = crate::datafile::test::TempDir::new("seed");
+        let path = dir.file("grid.dat");
+        let points: Vec<_> = (0..100)
+            .map(|i| ((i / 10) as f32, (i % 10) as f32))
+            .collect();
+        std::fs::write(&path, crate::datafile::test::write(&points, "x")).unwrap();
+        let source = DatasetSource::file(&path);
+        let generator = LocationGenerator::Datafile(DatafileLocationGenerator::new(
+            &[("world", &source)],
+            true,
+        ));
+        let locations = |seed| {
+            let mut seeded = settings(5);
+            seeded.seed = Some(seed);
+            let game = Game::new(seeded, &generator).unwrap();
+            (0..5)
+                .map(|i| {
+                    let location = game.get_location(i);
+                    (location.latitude, location.longitude)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(locations(1234), locations(1234));
+        assert_ne!(locations(1234), locations(4321));
+    }
+
+    #[test]
+    fn test_lobby() {
+        let mut game = Game::new(settings(5), &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Host").unwrap();
+        game.add_player(2, "Guest").unwrap();
+        assert!(game.is_host(1));
+        assert!(!game.is_host(2));
+        assert_eq!(game.get_player(2).unwrap().state, PlayerState::Joined);
+
+        game.start().unwrap();
+        assert_eq!(game.get_player(1).unwrap().state, PlayerState::Guessing);
+        assert_eq!(game.get_player(2).unwrap().state, PlayerState::Guessing);
+        assert_eq!(game.add_player(3, "Latecomer"), Err(Error::CannotAddPlayer));
+    }
+
+    #[test]
+    fn test_round_timeout() {
+        let mut settings = settings(2);
+        settings.round_time_limit = Some(30);
+        settings.results_time_limit = Some(10);
+        let mut game = Game::new(settings, &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Fast").unwrap();
+        game.add_player(2, "Slow").unwrap();
+        let start = Instant::now();
+        game.start().unwrap();
+
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        game.tick(start + Duration::from_secs(5));
+        assert_eq!(game.get_player(2).unwrap().state, PlayerState::Guessing);
+
+        // Slow ran out of time
+        game.tick(start + Duration::from_secs(31));
+        let slow = game.get_player(2).unwrap();
+        assert_eq!(slow.state, PlayerState::GuessResults);
+        assert_eq!(slow.points, 0);
+        assert!(slow.results[0].guess.is_none());
+        assert_eq!(game.get_player(1).unwrap().points, 10_000);
+        assert_eq!(game.get_locations_remaining(), 2);
+
+        // Results have been shown long enough, move on to the next location
+        game.tick(start + Duration::from_secs(45));
+        assert_eq!(game.get_locations_remaining(), 1);
+        assert_eq!(game.get_player(1).unwrap().state, PlayerState::Guessing);
+        assert_eq!(game.get_player(2).unwrap().state, PlayerState::Guessing);
+        assert!(game.get_time_remaining(Instant::now()).is_some());
+    }
+
+    #[test]
+    fn test_no_time_limit() {
+        let mut game = Game::new(settings(2), &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Slow").unwrap();
+        game.start().unwrap();
+        assert!(game.get_time_remaining(Instant::now()).is_none());
+        game.tick(Instant::now() + Duration::from_secs(1_000_000));
+        assert_eq!(game.get_player(1).unwrap().state, PlayerState::Guessing);
+    }
+
+    #[test]
+    fn test_view_hides_location() {
+        let mut game = Game::new(hosted(2), &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Host").unwrap();
+        game.add_player(2, "Guest").unwrap();
+        assert!(game.view().current_location.is_none());
+
+        game.start().unwrap();
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        let view = game.view();
+        assert_eq!(view.player_states[&1], PlayerState::GuessResults);
+        assert_eq!(view.player_states[&2], PlayerState::Guessing);
+        assert_eq!(view.player_points[&1], 10_000);
+        assert!(!view.everyone_guessed);
+        assert!(view.current_location.is_none());
+
+        game.guess(2, &guess, &LocationGenerator::mock()).unwrap();
+        let view = game.view();
+        assert!(view.everyone_guessed);
+        assert!(view.current_location.is_some());
+    }
+
+    #[test]
+    fn test_guess_errors() {
+        let mut game = Game::new(hosted(2), &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Host").unwrap();
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        assert_eq!(
+            game.guess(1, &guess, &LocationGenerator::mock())
+                .unwrap_err(),
+            Error::WrongState(PlayerState::Joined)
+        );
+
+        game.start().unwrap();
+        assert_eq!(
+            game.guess(2, &guess, &LocationGenerator::mock())
+                .unwrap_err(),
+            Error::UnknownPlayer(2)
+        );
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        // Submitting the same guess twice doesn't count twice
+        assert_eq!(
+            game.guess(1, &guess, &LocationGenerator::mock())
+                .unwrap_err(),
+            Error::AlreadyGuessed
+        );
+        assert_eq!(game.get_player(1).unwrap().points, 10_000);
+        assert_eq!(game.get_player(1).unwrap().results.len(), 1);
+    }
+
+    #[test]
+    fn test_start() {
+        let mut game = Game::new(settings(2), &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Host").unwrap();
+        assert_eq!(
+            game.advance_guess(),
+            Err(Error::WrongState(PlayerState::Joined))
+        );
+        assert_eq!(game.start(), Ok(()));
+        assert_eq!(game.start(), Err(Error::AlreadyStarted));
+        assert_eq!(game.add_player(2, "Late"), Err(Error::CannotAddPlayer));
+        assert_eq!(game.get_player(1).unwrap().state, PlayerState::Guessing);
+    }
+
+    #[test]
+    fn test_advance_waits_for_everyone() {
+        let mut game = Game::new(hosted(2), &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Fast").unwrap();
+        game.add_player(2, "Slow").unwrap();
+        game.start().unwrap();
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+
+        // The fast player can't skip the slow player's turn
+        assert_eq!(game.advance_guess(), Err(Error::WaitingForPlayers));
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        assert_eq!(game.advance_guess(), Err(Error::WaitingForPlayers));
+        assert_eq!(game.get_round(), 1);
+        assert_eq!(game.get_player(1).unwrap().state, PlayerState::GuessResults);
+
+        game.guess(2, &guess, &LocationGenerator::mock()).unwrap();
+        assert_eq!(game.advance_guess(), Ok(()));
+        assert_eq!(game.get_round(), 2);
+        assert_eq!(game.get_player(1).unwrap().state, PlayerState::Guessing);
+        assert_eq!(game.get_player(2).unwrap().state, PlayerState::Guessing);
+    }
+
+    #[test]
+    fn test_game_over() {
+        let mut game = Game::new(hosted(1), &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Host").unwrap();
+        game.start().unwrap();
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        game.advance_guess().unwrap();
+        assert_eq!(game.get_player(1).unwrap().state, PlayerState::FinalResults);
+
+        assert_eq!(
+            game.guess(1, &guess, &LocationGenerator::mock())
+                .unwrap_err(),
+            Error::GameOver
+        );
+        assert_eq!(game.advance_guess(), Err(Error::GameOver));
+        assert_eq!(game.start(), Err(Error::AlreadyStarted));
+        assert_eq!(game.get_player(1).unwrap().points, 10_000);
+    }
+
+    fn three_player_game(advance: AdvancePolicy) -> Game {
+        let mut settings = settings(3);
+        settings.advance = advance;
+        let mut game = Game::new(settings, &LocationGenerator::mock()).unwrap();
+        for (id, name) in [(1, "Host"), (2, "Second"), (3, "Third")].iter() {
+            game.add_player(*id, name).unwrap();
+        }
+        game.start().unwrap();
+        game
+    }
+
+    #[test]
+    fn test_advance_automatic() {
+        let mut game = three_player_game(AdvancePolicy::Automatic);
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        assert_eq!(game.ready(1), Err(Error::WrongState(PlayerState::Guessing)));
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        game.guess(2, &guess, &LocationGenerator::mock()).unwrap();
+        // Being ready doesn't hurry the last player along
+        assert_eq!(game.ready(1), Ok(false));
+        assert_eq!(game.get_round(), 1);
+        game.guess(3, &guess, &LocationGenerator::mock()).unwrap();
+        assert_eq!(game.get_round(), 2);
+        assert!(!game.get_player(1).unwrap().ready);
+    }
+
+    #[test]
+    fn test_advance_automatic_without_ready() {
+        let mut game = three_player_game(AdvancePolicy::Automatic);
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        for round in 1..=3 {
+            assert_eq!(game.get_round(), round);
+            for id in 1..=3 {
+                game.guess(id, &guess, &LocationGenerator::mock()).unwrap();
+            }
+        }
+        assert!(game.is_finished());
+    }
+
+    #[test]
+    fn test_advance_host() {
+        let mut game = three_player_game(AdvancePolicy::Host);
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        assert_eq!(game.ready(1), Err(Error::WaitingForPlayers));
+        game.guess(2, &guess, &LocationGenerator::mock()).unwrap();
+        game.guess(3, &guess, &LocationGenerator::mock()).unwrap();
+        assert_eq!(game.ready(2), Ok(false));
+        assert_eq!(game.ready(3), Ok(false));
+        assert_eq!(game.get_round(), 1);
+        assert_eq!(game.ready(1), Ok(true));
+        assert_eq!(game.get_round(), 2);
+    }
+
+    #[test]
+    fn test_advance_host_ignores_timer() {
+        let mut settings = settings(2);
+        settings.advance = AdvancePolicy::Host;
+        settings.results_time_limit = Some(5);
+        let mut game = Game::new(settings, &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Host").unwrap();
+        game.start().unwrap();
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        game.tick(Instant::now() + Duration::from_secs(6));
+        assert_eq!(game.get_round(), 1);
+        assert_eq!(game.ready(1), Ok(true));
+        assert_eq!(game.get_round(), 2);
+    }
+
+    #[test]
+    fn test_advance_vote() {
+        let mut game = three_player_game(AdvancePolicy::Vote);
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        game.guess(2, &guess, &LocationGenerator::mock()).unwrap();
+        assert_eq!(game.ready(1), Ok(false));
+        // Two out of three is enough, and the third player misses out
+        assert_eq!(game.ready(2), Ok(true));
+        assert_eq!(game.get_round(), 2);
+        let third = game.get_player(3).unwrap();
+        assert_eq!(third.state, PlayerState::Guessing);
+        assert!(third.results[0].guess.is_none());
+        assert_eq!(third.points, 0);
+    }
+
+    #[test]
+    fn test_advance_timer() {
+        let mut settings = settings(2);
+        settings.advance = AdvancePolicy::Timer;
+        settings.results_time_limit = Some(5);
+        let mut game = Game::new(settings, &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Host").unwrap();
+        game.start().unwrap();
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        assert_eq!(game.ready(1), Ok(false));
+        assert_eq!(game.get_round(), 1);
+        game.tick(Instant::now() + Duration::from_secs(6));
+        assert_eq!(game.get_round(), 2);
+    }
+
+    #[test]
+    fn test_events() {
+        let mut game = Game::new(hosted(1), &LocationGenerator::mock()).unwrap();
+        let events = game.subscribe();
+        game.add_player(1, "Host").unwrap();
+        game.start().unwrap();
+        let guess = LocationGenerator::mock()
+            .sample_from_dataset("world")
+            .unwrap();
+        game.guess(1, &guess, &LocationGenerator::mock()).unwrap();
+        game.ready(1).unwrap();
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                GameEvent::PlayerJoined {
+                    player: 1,
+                    name: "Host".to_string()
+                },
+                GameEvent::GameStarted,
+                GameEvent::PlayerGuessed { player: 1 },
+                GameEvent::PlayerReady { player: 1 },
+                GameEvent::GameFinished,
+            ]
+        );
+
+        // Once the game is gone, so are the events
+        drop(game);
+        assert!(events.recv().is_err());
+    }
+
+    #[test]
+    fn test_time_bonus() {
+        let mut settings = settings(1);
+        settings.scoring = ScoringCurve::TimeBonus;
+        let mut game = Game::new(settings, &LocationGenerator::mock()).unwrap();
+        game.add_player(1, "Quick").unwrap();
+        game.start().unwrap();
+        let result = game
+            .guess(
+                1,
+                &LocationGenerator::mock()
+                    .sample_from_dataset("world")
+                    .unwrap(),
+                &LocationGenerator::mock(),
+            )
+            .unwrap();
+        assert_eq!(result.breakdown.distance_points, 10_000);
+        assert!(result.breakdown.time_bonus > 1_900);
+        assert_eq!(result.points_gained, result.breakdown.total());
+        assert_eq!(game.get_player(1).unwrap().points, result.points_gained);
+        assert_eq!(result.right_country, None);
+    }
+
+    #[test]
+    fn test_right_country() {
+        use crate::composite::DatasetSource;
+        use crate::location_generator::DatafileLocationGenerator;
+
+        let dir = crate::datafile::test::TempDir::new("country");
+        let path = dir.file("world.dat");
+        let points = [(31.0, -100.0), (31.5, -99.5), (47.0, 2.0), (47.5, 2.5)];
+        std::fs::write(
+            &path,
+            crate::datafile::test::write_regions(&[("US-TX", 2), ("FR-IDF", 2)], &points, "x"),
+        )
+        .unwrap();
+        let source = DatasetSource::file(&path);
+        let generator = LocationGenerator::Datafile(DatafileLocationGenerator::new(
+            &[("world", &source)],
+            true,
+        ));
+
+        let mut settings = settings(1);
+        settings.scoring = ScoringCurve::CountryBonus;
+        let mut game = Game::new(settings, &generator).unwrap();
+        game.add_player(1, "Close").unwrap();
+        game.add_player(2, "Lost").unwrap();
+        game.add_player(3, "Across").unwrap();
+        game.start().unwrap();
+        let actual = game.get_current_location();
+        let spot_on = actual.clone();
+        // Somewhere the dataset has no points, which could be over a border
+        let across = Location {
+            latitude: actual.latitude - 0.3,
+            longitude: actual.longitude,
+        };
+        // One of the other country's points
+        let elsewhere = points
+            .iter()
+            .map(|(latitude, longitude)| Location {
+                latitude: *latitude as f64,
+                longitude: *longitude as f64,
+            })
+            .find(|point| (point.latitude - actual.latitude).abs() > 10.0)
+            .unwrap();
+        let close = game.guess(1, &spot_on, &generator).unwrap();
+        assert_eq!(close.right_country, Some(true));
+        assert_eq!(close.breakdown.country_bonus, 2_000);
+        let lost = game.guess(2, &elsewhere, &generator).unwrap();
+        assert_eq!(lost.right_country, Some(false));
+        assert_eq!(lost.breakdown.country_bonus, 0);
+        let unknown = game.guess(3, &across, &generator).unwrap();
+        assert_eq!(unknown.right_country, None);
+        assert_eq!(unknown.breakdown.country_bonus, 0);
+    }
+
     #[test]
     fn test_singleplayer_game() {
-        let mut game = Game::new(2, &LocationGenerator::mock(), "world");
+        let mut game = Game::new(hosted(2), &LocationGenerator::mock()).unwrap();
         game.add_player(1234, "MyNickname").unwrap();
         assert_eq!(game.get_player(1234).unwrap().state, PlayerState::Joined);
 
-        game.start();
+        game.start().unwrap();
         assert_eq!(game.everyone_guessed(), false);
         assert_eq!(game.is_finished(), false);
         assert_eq!(game.get_player(1234).unwrap().state, PlayerState::Guessing);
 
         let guess_result = game.guess(
             1234,
-            &LocationGenerator::mock().sample_from_dataset("world"),
+            &LocationGenerator::mock()
+                .sample_from_dataset("world")
+                .unwrap(),
+            &LocationGenerator::mock(),
         );
         assert_eq!(guess_result.unwrap().points_gained, 10_000);
         assert_eq!(game.get_player(1234).unwrap().points, 10_000);
@@ -261,7 +1177,10 @@ mod test {
 
         let guess_result = game.guess(
             1234,
-            &LocationGenerator::mock().sample_from_dataset("world"),
+            &LocationGenerator::mock()
+                .sample_from_dataset("world")
+                .unwrap(),
+            &LocationGenerator::mock(),
         );
         assert_eq!(guess_result.unwrap().points_gained, 10_000);
         assert_eq!(game.get_player(1234).unwrap().points, 20_000);
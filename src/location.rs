@@ -5,7 +5,7 @@
 //use rocket::response::Redirect;
 //use rocket::State;
 //use rocket_contrib::templates::Template;
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 //use std::collections::HashMap;
 //use std::sync::Arc;
 //use std::sync::Mutex;
@@ -17,7 +17,7 @@ fn deg2rad(x: f64) -> f64 {
     3.14159265 * x / 180.0
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Location {
     pub latitude: f64,
     pub longitude: f64,
@@ -1,60 +1,766 @@
-use rand::Rng;
-use std::collections::HashMap;
-use std::convert::TryInto;
+use rand::{Rng, RngCore};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
 
-use crate::location::Location;
+use crate::composite::{region_contains, BoundingBox, Composite, DatasetSource};
+use crate::datafile::{Dataset, Header, LoadError};
+use crate::location::{DistanceKm, Location};
 
 pub trait LocationGeneratorTrait {
-    fn sample_from_dataset(&self, dataset: &str) -> Location;
+    /// Picks a location using the given source of randomness, so that the same seed
+    /// always picks the same locations
+    fn sample_with_rng(
+        &self,
+        dataset: &str,
+        rng: &mut dyn RngCore,
+    ) -> Result<Location, SampleError>;
+
+    fn sample_from_dataset(&self, dataset: &str) -> Result<Location, SampleError> {
+        self.sample_with_rng(dataset, &mut rand::thread_rng())
+    }
+
+    /// Whether there are any locations to be had from the dataset
+    fn has_dataset(&self, dataset: &str) -> bool;
+
+    /// Picks `count` different locations, all at least `min_spacing` apart from each other
+    fn sample_spaced(
+        &self,
+        dataset: &str,
+        count: usize,
+        min_spacing: DistanceKm,
+        rng: &mut dyn RngCore,
+    ) -> Result<Vec<Location>, SampleError>;
+
+    /// How far apart the points in the dataset can be, if we know
+    fn dataset_extent(&self, dataset: &str) -> Option<DistanceKm>;
+
+    /// Whether the dataset says which country or region its points are in
+    fn has_regions(&self, dataset: &str) -> bool;
+
+    /// The ISO 3166 code of the country or region the location is in, going by the
+    /// dataset's points right around it, if there are any and they say
+    fn region_at(&self, dataset: &str, location: &Location) -> Option<String>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SampleError {
+    /// The dataset isn't in the config, or didn't load
+    UnknownDataset(String),
+
+    /// There aren't enough locations in the dataset which are far enough apart, at least
+    /// among the ones that were looked at
+    NotEnoughLocations {
+        wanted: usize,
+        found: usize,
+        min_spacing: DistanceKm,
+    },
+}
+
+impl fmt::Display for SampleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SampleError::UnknownDataset(dataset) => {
+                write!(f, "There are no locations for {:?}.", dataset)
+            }
+            SampleError::NotEnoughLocations {
+                wanted,
+                found,
+                min_spacing,
+            } => write!(
+                f,
+                "There are only {} locations in that place at least {}km apart, not {}. \
+                 Try fewer locations or a smaller spacing.",
+                found, min_spacing, wanted
+            ),
+        }
+    }
+}
+
+/// How many random picks to try before going through the dataset in order instead
+const RANDOM_ATTEMPTS_PER_LOCATION: usize = 100;
+
+/// How many points to go through in order before giving up, so that a big dataset
+/// without room for the game doesn't get read through end to end
+const MAX_POINTS_IN_ORDER: usize = 100_000;
+
+/// How many of a dataset's points to look at to find out if it crosses the antimeridian
+const EXTENT_SAMPLES: usize = 10_000;
+
+/// How big the cells of a `RegionGrid` are, in degrees. About 11km north to south, so
+/// that not too many of them straddle a border.
+const REGION_CELL_DEGREES: f64 = 0.1;
+
+fn cell(location: &Location) -> (i32, i32) {
+    (
+        (location.latitude / REGION_CELL_DEGREES).floor() as i32,
+        (location.longitude / REGION_CELL_DEGREES).floor() as i32,
+    )
+}
+
+/// Which region most of a file's points in each part of the map are in, for working out
+/// where locations that aren't in the file are, like guesses
+struct RegionGrid {
+    /// The code of each of the file's regions, in order
+    regions: Vec<String>,
+
+    /// Which of the regions each cell with any points in it has
+    cells: HashMap<(i32, i32), usize>,
+}
+
+impl RegionGrid {
+    /// None for files without regions. Reads through all the points, so that it's done
+    /// with when the file loads rather than when somebody's waiting on a guess.
+    fn new(file: &Dataset, header: Option<&Header>) -> Option<RegionGrid> {
+        let regions = &header?.regions;
+        if regions.iter().all(|region| region.code.is_empty()) {
+            return None;
+        }
+        // The region with the most points in each cell, and how many
+        let mut best: HashMap<(i32, i32), (usize, usize)> = HashMap::new();
+        let mut start = 0;
+        for (index, region) in regions.iter().enumerate() {
+            let end = start + region.count as usize;
+            let mut counts: HashMap<(i32, i32), usize> = HashMap::new();
+            for idx in start..end {
+                *counts.entry(cell(&file.get(idx))).or_default() += 1;
+            }
+            for (cell, count) in counts {
+                let entry = best.entry(cell).or_insert((index, 0));
+                if count > entry.1 {
+                    *entry = (index, count);
+                }
+            }
+            start = end;
+        }
+        Some(RegionGrid {
+            regions: regions.iter().map(|region| region.code.clone()).collect(),
+            cells: best
+                .into_iter()
+                .map(|(cell, (region, _))| (cell, region))
+                .collect(),
+        })
+    }
+
+    /// The region of the location's cell, if it has any points in it. Cells without any
+    /// are left alone, rather than going by the points around them, since the other side
+    /// of a border might not be in the file at all. The region is empty where most of the
+    /// points aren't in any country.
+    fn lookup(&self, location: &Location) -> Option<&str> {
+        let region = self.cells.get(&cell(location))?;
+        Some(&self.regions[*region])
+    }
+}
+
+/// Which of a file's points are in a segment
+#[derive(Clone)]
+enum Selection {
+    /// A run of them, like one region's
+    Range(Range<usize>),
+
+    /// Particular ones, picked out from the rest. Unlike the rest of the dataset these
+    /// are held in memory, at 4 bytes a point.
+    Indices(Arc<Vec<u32>>),
+}
+
+/// Some or all of the points in one file
+#[derive(Clone)]
+struct Segment {
+    file: Arc<Dataset>,
+    selection: Selection,
+
+    /// Where the file's regions are, if it has any
+    grid: Option<Arc<RegionGrid>>,
+
+    /// The ISO 3166 code of the country or region the points are in, if the file says
+    region: Option<String>,
+
+    /// How often to pick from it, compared to the rest of the dataset's segments
+    weight: f64,
+
+    /// The bounding box of the points
+    min: Location,
+    max: Location,
+}
+
+impl Segment {
+    /// A segment for each of the file's regions, or one for the whole file if it doesn't
+    /// have any
+    fn split(file: Dataset, header: Option<&Header>) -> Vec<Segment> {
+        let grid = RegionGrid::new(&file, header).map(Arc::new);
+        let file = Arc::new(file);
+        let regions = header.map_or(&[][..], |header| &header.regions);
+        if regions.is_empty() {
+            // Only old files without a header need reading through for their bounding box
+            let (min, max) = match header {
+                Some(header) => (header.min.clone(), header.max.clone()),
+                None => bounding_box(file.iter()).unwrap(),
+            };
+            return vec![Segment {
+                selection: Selection::Range(0..file.len()),
+                grid,
+                region: None,
+                weight: file.len() as f64,
+                file,
+                min,
+                max,
+            }];
+        }
+        let mut start = 0;
+        regions
+            .iter()
+            .filter_map(|region| {
+                let range = start..start + region.count as usize;
+                start = range.end;
+                if range.is_empty() {
+                    return None;
+                }
+                Some(Segment {
+                    file: file.clone(),
+                    grid: grid.clone(),
+                    weight: range.len() as f64,
+                    selection: Selection::Range(range),
+                    region: Some(region.code.clone()).filter(|code| !code.is_empty()),
+                    min: region.min.clone(),
+                    max: region.max.clone(),
+                })
+            })
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        match &self.selection {
+            Selection::Range(range) => range.len(),
+            Selection::Indices(indices) => indices.len(),
+        }
+    }
+
+    /// Where the segment's `idx`th point is in the file
+    fn file_index(&self, idx: usize) -> usize {
+        match &self.selection {
+            Selection::Range(range) => range.start + idx,
+            Selection::Indices(indices) => indices[idx] as usize,
+        }
+    }
+
+    fn get(&self, idx: usize) -> Location {
+        self.file.get(self.file_index(idx))
+    }
+
+    /// Only the points in the region, or None if there aren't any
+    fn within(
+        &self,
+        bbox: &Option<BoundingBox>,
+        polygon: &Option<Vec<[f64; 2]>>,
+    ) -> Option<Segment> {
+        let indices: Vec<u32> = (0..self.len())
+            .filter(|idx| region_contains(bbox, polygon, &self.get(*idx)))
+            .map(|idx| self.file_index(idx) as u32)
+            .collect();
+        let (min, max) = bounding_box(indices.iter().map(|idx| self.file.get(*idx as usize)))?;
+        Some(Segment {
+            weight: self.weight * indices.len() as f64 / self.len() as f64,
+            file: self.file.clone(),
+            grid: self.grid.clone(),
+            selection: Selection::Indices(Arc::new(indices)),
+            region: self.region.clone(),
+            min,
+            max,
+        })
+    }
+}
+
+/// Which segment, and which point in it
+type PointId = (usize, usize);
+
+/// A dataset's points, which might come from several files
+#[derive(Clone)]
+struct Points {
+    /// Never empty, and none of them are empty
+    segments: Vec<Segment>,
+
+    /// The total weight of the segments up to and including each one, so that picking
+    /// between thousands of regions doesn't mean going through them all
+    cumulative: Vec<f64>,
+
+    /// Worked out when it's loaded, rather than for every game
+    extent: DistanceKm,
+}
+
+impl Points {
+    fn new(segments: Vec<Segment>) -> Points {
+        let cumulative = segments
+            .iter()
+            .scan(0.0, |total, segment| {
+                *total += segment.weight;
+                Some(*total)
+            })
+            .collect();
+        let mut points = Points {
+            segments,
+            cumulative,
+            extent: 0.0,
+        };
+        points.extent = points.measure_extent();
+        points
+    }
+
+    fn total_weight(&self) -> f64 {
+        *self.cumulative.last().unwrap()
+    }
+
+    fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.len()).sum()
+    }
+
+    /// Datasets that are one whole file without regions don't spend any randomness
+    /// picking the segment, and the point is drawn the same way as it was before there
+    /// were segments, so that a seed picks the same locations as it always has
+    fn pick(&self, rng: &mut dyn RngCore) -> PointId {
+        let mut segment = 0;
+        if self.segments.len() > 1 {
+            let target = rng.gen::<f64>() * self.total_weight();
+            segment = self
+                .cumulative
+                .partition_point(|total| *total <= target)
+                .min(self.segments.len() - 1);
+        }
+        let idx: usize = rng.gen();
+        (segment, idx % self.segments[segment].len())
+    }
+
+    fn get(&self, (segment, idx): PointId) -> Location {
+        self.segments[segment].get(idx)
+    }
+
+    /// Every point, starting from the `start`th and wrapping around
+    fn all_from(&self, start: usize) -> impl Iterator<Item = PointId> + '_ {
+        // Skip whole segments at a time to get to the start
+        let (mut first, mut offset) = (0, start);
+        while offset >= self.segments[first].len() {
+            offset -= self.segments[first].len();
+            first += 1;
+        }
+        let count = self.segments.len();
+        (0..=count).flat_map(move |step| {
+            let segment = (first + step) % count;
+            let len = self.segments[segment].len();
+            let idxs = match step {
+                0 => offset..len,
+                step if step == count => 0..offset,
+                _ => 0..len,
+            };
+            idxs.map(move |idx| (segment, idx))
+        })
+    }
+
+    /// Scales the weights, so that they add up to `weight`
+    fn weighted(&self, weight: f64) -> Vec<Segment> {
+        let scale = weight / self.total_weight();
+        self.segments
+            .iter()
+            .map(|segment| Segment {
+                weight: segment.weight * scale,
+                ..segment.clone()
+            })
+            .collect()
+    }
+
+    fn has_regions(&self) -> bool {
+        self.segments.iter().any(|segment| segment.grid.is_some())
+    }
+
+    /// Where the location is, going by the points around it in any of the files with regions
+    fn region_at(&self, location: &Location) -> Option<&str> {
+        let mut grids: Vec<&Arc<RegionGrid>> = vec![];
+        for grid in self
+            .segments
+            .iter()
+            .filter_map(|segment| segment.grid.as_ref())
+        {
+            if !grids.iter().any(|seen| Arc::ptr_eq(seen, grid)) {
+                grids.push(grid);
+            }
+        }
+        let region = grids.iter().find_map(|grid| grid.lookup(location))?;
+        Some(region).filter(|region| !region.is_empty())
+    }
+
+    /// The diagonal of the bounding box around the points, going the short way around
+    /// for places that cross the antimeridian, like Alaska or Fiji
+    fn measure_extent(&self) -> DistanceKm {
+        let corners = self
+            .segments
+            .iter()
+            .flat_map(|segment| vec![segment.min.clone(), segment.max.clone()]);
+        let (mut min, mut max) = bounding_box(corners).unwrap();
+        if let Some((west, east)) = self.antimeridian_span() {
+            min.longitude = west;
+            max.longitude = east;
+        }
+        min.distance_to(&max)
+    }
+
+    /// Where the points start and end, west to east, if they go over the antimeridian.
+    /// Goes by some of the points spread through the segments, looking for the widest
+    /// stretch of longitudes without any, which is the antimeridian unless it's crossed.
+    fn antimeridian_span(&self) -> Option<(f64, f64)> {
+        let step = (self.len() / EXTENT_SAMPLES).max(1);
+        let mut longitudes: Vec<f64> = self
+            .segments
+            .iter()
+            .flat_map(|segment| {
+                (0..segment.len())
+                    .step_by(step)
+                    .map(move |idx| segment.get(idx).longitude)
+            })
+            .collect();
+        longitudes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let around = longitudes.first()? + 360.0 - longitudes.last()?;
+        let (gap, east, west) = longitudes
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0], pair[0], pair[1]))
+            .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap())?;
+        if gap > around {
+            Some((west, east))
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the point can go in a game with the ones already picked
+fn fits(points: &Points, picked: &HashSet<PointId>, id: PointId, min_spacing: DistanceKm) -> bool {
+    if picked.contains(&id) {
+        return false;
+    }
+    let location = points.get(id);
+    picked
+        .iter()
+        .all(|other| points.get(*other).distance_to(&location) >= min_spacing)
+}
+
+/// How loading one of the datasets went
+#[derive(Debug)]
+pub struct DatasetReport {
+    pub name: String,
+
+    /// The file, or what the composite is made of
+    pub source: String,
+
+    /// How many locations it has
+    pub result: Result<usize, LoadError>,
+
+    /// None for composites, and files from before there were headers
+    pub header: Option<Header>,
+}
+
+impl fmt::Display for DatasetReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.result {
+            Ok(count) => {
+                write!(f, "{} ({}): {} locations", self.name, self.source, count)?;
+                match &self.header {
+                    Some(header) => {
+                        write!(
+                            f,
+                            ", made by {} from {} on {}",
+                            header.generator,
+                            header.source,
+                            header.created_day()
+                        )?;
+                        match header.regions.len() {
+                            0 => Ok(()),
+                            regions => write!(f, ", in {} regions", regions),
+                        }
+                    }
+                    None if self.source.ends_with(".dat") => {
+                        write!(
+                            f,
+                            ", in the old format without a header, so it had to be read \
+                             through for its bounding box (regenerate it to start faster)"
+                        )
+                    }
+                    None => Ok(()),
+                }
+            }
+            Err(e) => write!(
+                f,
+                "{} ({}): not available, because {}",
+                self.name, self.source, e
+            ),
+        }
+    }
 }
 
 pub struct DatafileLocationGenerator {
-    //points: Vec<Location>,
-    datasets: HashMap<String, Vec<Location>>,
-}
-
-//unsafe impl Send for DatafileLocationGenerator {}
-//unsafe impl Sync for DatafileLocationGenerator {}
-
-fn load_file(filename: &str) -> Vec<Location> {
-    let contents = std::fs::read(filename).unwrap();
-    let mut idx = 0;
-    let mut points = vec![];
-    while idx < contents.len() {
-        let lat = f32::from_le_bytes(contents[idx..idx + 4].try_into().unwrap());
-        let lon = f32::from_le_bytes(contents[idx + 4..idx + 8].try_into().unwrap());
-        idx += 8;
-        points.push(Location {
-            latitude: lat as f64,
-            longitude: lon as f64,
+    /// Only datasets that loaded
+    datasets: HashMap<String, Points>,
+    report: Vec<DatasetReport>,
+}
+
+fn bounding_box(mut points: impl Iterator<Item = Location>) -> Option<(Location, Location)> {
+    let first = points.next()?;
+    let (mut min, mut max) = (first.clone(), first);
+    for point in points {
+        min.latitude = min.latitude.min(point.latitude);
+        min.longitude = min.longitude.min(point.longitude);
+        max.latitude = max.latitude.max(point.latitude);
+        max.longitude = max.longitude.max(point.longitude);
+    }
+    Some((min, max))
+}
+
+/// Works out the composites, after whatever they're made from
+struct CompositeLoader<'a> {
+    composites: HashMap<&'a str, &'a Composite>,
+    datasets: HashMap<String, Points>,
+    results: HashMap<String, Result<usize, LoadError>>,
+
+    /// The composites part way through being worked out, to catch any made from themselves
+    loading: Vec<&'a str>,
+}
+
+impl<'a> CompositeLoader<'a> {
+    fn part(&mut self, place: &'a str) -> Result<Points, LoadError> {
+        if self.loading.contains(&place) {
+            return Err(LoadError::Circular(place.to_string()));
+        }
+        if !self.results.contains_key(place) {
+            if let Some(composite) = self.composites.get(place).copied() {
+                self.load(place, composite);
+            }
+        }
+        match (self.datasets.get(place), self.results.get(place)) {
+            (Some(points), _) => Ok(points.clone()),
+            // Whatever it's part of is in the same circle
+            (None, Some(Err(LoadError::Circular(circular)))) => {
+                Err(LoadError::Circular(circular.clone()))
+            }
+            (None, _) => Err(LoadError::MissingPart(place.to_string())),
+        }
+    }
+
+    fn load(&mut self, name: &'a str, composite: &'a Composite) {
+        self.loading.push(name);
+        let result = self.build(composite);
+        self.loading.pop();
+        let result = result.map(|points| {
+            let count = points.len();
+            self.datasets.insert(name.to_string(), points);
+            count
         });
+        self.results.insert(name.to_string(), result);
+    }
+
+    fn build(&mut self, composite: &'a Composite) -> Result<Points, LoadError> {
+        let mut segments = vec![];
+        match composite {
+            Composite::Mix { parts } => {
+                for part in parts.iter() {
+                    if !(part.weight > 0.0 && part.weight.is_finite()) {
+                        return Err(LoadError::BadWeights);
+                    }
+                    segments.extend(self.part(&part.place)?.weighted(part.weight));
+                }
+            }
+            Composite::Union { places } => {
+                for place in places.iter() {
+                    let points = self.part(place)?;
+                    segments.extend(points.weighted(points.len() as f64));
+                }
+            }
+            Composite::Within {
+                place,
+                bbox,
+                polygon,
+            } => {
+                let points = self.part(place)?;
+                segments.extend(
+                    points
+                        .segments
+                        .iter()
+                        .filter_map(|segment| segment.within(bbox, polygon)),
+                );
+            }
+            Composite::Balanced { place, by, weights } => {
+                if !weights.values().all(|w| *w >= 0.0 && w.is_finite()) {
+                    return Err(LoadError::BadWeights);
+                }
+                let points = self.part(place)?;
+                // How many points each country or region has, so that they can be spread
+                // evenly across its segments
+                let mut sizes: HashMap<&str, usize> = HashMap::new();
+                for segment in points.segments.iter() {
+                    if let Some(key) = segment.region.as_deref().and_then(|code| by.key(code)) {
+                        *sizes.entry(key).or_default() += segment.len();
+                    }
+                }
+                if sizes.is_empty() {
+                    return Err(LoadError::NoRegions);
+                }
+                // Points outside any country are left out
+                for segment in points.segments.iter() {
+                    if let Some(key) = segment.region.as_deref().and_then(|code| by.key(code)) {
+                        let weight = weights.get(key).copied().unwrap_or(1.0);
+                        if weight > 0.0 {
+                            segments.push(Segment {
+                                weight: weight * segment.len() as f64 / sizes[key] as f64,
+                                ..segment.clone()
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        if segments.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        Ok(Points::new(segments))
     }
-    points
 }
 
 impl DatafileLocationGenerator {
-    pub fn new(datasets: &[(&str, &str)]) -> DatafileLocationGenerator {
-        let mut gen = DatafileLocationGenerator {
+    /// With `verify`, every file gets read through to check its checksum, rather than
+    /// just its header
+    pub fn new(datasets: &[(&str, &DatasetSource)], verify: bool) -> DatafileLocationGenerator {
+        let mut loader = CompositeLoader {
+            composites: HashMap::new(),
             datasets: HashMap::new(),
+            results: HashMap::new(),
+            loading: vec![],
         };
-        for (name, filename) in datasets.iter() {
-            gen.datasets.insert(name.to_string(), load_file(filename));
+        let mut headers = HashMap::new();
+        for (name, source) in datasets.iter() {
+            match source {
+                DatasetSource::File { filename } => {
+                    let result = Dataset::open(filename, verify).map(|(header, file)| {
+                        let points = Points::new(Segment::split(file, header.as_ref()));
+                        if let Some(header) = header {
+                            headers.insert(name.to_string(), header);
+                        }
+                        let count = points.len();
+                        loader.datasets.insert(name.to_string(), points);
+                        count
+                    });
+                    loader.results.insert(name.to_string(), result);
+                }
+                DatasetSource::Composite { composite } => {
+                    loader.composites.insert(name, composite);
+                }
+            }
+        }
+        for (name, source) in datasets.iter() {
+            if let DatasetSource::Composite { composite } = source {
+                if !loader.results.contains_key(*name) {
+                    loader.load(name, composite);
+                }
+            }
+        }
+
+        let report = datasets
+            .iter()
+            .map(|(name, source)| DatasetReport {
+                name: name.to_string(),
+                source: source.to_string(),
+                result: loader.results.remove(*name).unwrap(),
+                header: headers.remove(*name),
+            })
+            .collect();
+        for (name, points) in loader.datasets.iter() {
+            log::info!("{} spans {:.0}km", name, points.extent);
+        }
+        DatafileLocationGenerator {
+            datasets: loader.datasets,
+            report,
         }
-        gen
+    }
+
+    /// How each of the datasets it was given loaded, in the same order
+    pub fn report(&self) -> &[DatasetReport] {
+        &self.report
+    }
+
+    fn points(&self, dataset: &str) -> Result<&Points, SampleError> {
+        self.datasets
+            .get(dataset)
+            .ok_or_else(|| SampleError::UnknownDataset(dataset.to_string()))
     }
 }
 
 impl LocationGeneratorTrait for DatafileLocationGenerator {
-    fn sample_from_dataset(&self, dataset: &str) -> Location {
-        let mut rng = rand::thread_rng();
-        let idx: usize = rng.gen();
-        match self.datasets.get(dataset) {
-            Some(points) => points[idx % points.len()].clone(),
-            None => {
-                let points = self.datasets.get("world").unwrap();
-                points[idx % points.len()].clone()
+    fn sample_with_rng(
+        &self,
+        dataset: &str,
+        rng: &mut dyn RngCore,
+    ) -> Result<Location, SampleError> {
+        let points = self.points(dataset)?;
+        Ok(points.get(points.pick(rng)))
+    }
+
+    fn has_dataset(&self, dataset: &str) -> bool {
+        self.datasets.contains_key(dataset)
+    }
+
+    fn sample_spaced(
+        &self,
+        dataset: &str,
+        count: usize,
+        min_spacing: DistanceKm,
+        rng: &mut dyn RngCore,
+    ) -> Result<Vec<Location>, SampleError> {
+        let points = self.points(dataset)?;
+        let mut picked = HashSet::new();
+        let mut order = vec![];
+        for _ in 0..count * RANDOM_ATTEMPTS_PER_LOCATION {
+            if order.len() == count {
+                break;
             }
+            let id = points.pick(rng);
+            if fits(points, &picked, id, min_spacing) {
+                picked.insert(id);
+                order.push(id);
+            }
+        }
+        // Random picks keep missing, so the dataset is small or crowded. Go through it
+        // (from somewhere random), to be sure whether there's enough room, as long as that
+        // doesn't take too long.
+        if order.len() < count {
+            let start = rng.gen_range(0, points.len());
+            for id in points.all_from(start).take(MAX_POINTS_IN_ORDER) {
+                if order.len() == count {
+                    break;
+                }
+                if fits(points, &picked, id, min_spacing) {
+                    picked.insert(id);
+                    order.push(id);
+                }
+            }
+        }
+        if order.len() < count {
+            return Err(SampleError::NotEnoughLocations {
+                wanted: count,
+                found: order.len(),
+                min_spacing,
+            });
         }
+        Ok(order.into_iter().map(|id| points.get(id)).collect())
+    }
+
+    fn dataset_extent(&self, dataset: &str) -> Option<DistanceKm> {
+        self.datasets.get(dataset).map(|points| points.extent)
+    }
+
+    fn has_regions(&self, dataset: &str) -> bool {
+        self.datasets
+            .get(dataset)
+            .map_or(false, |points| points.has_regions())
+    }
+
+    fn region_at(&self, dataset: &str, location: &Location) -> Option<String> {
+        let points = self.datasets.get(dataset)?;
+        points.region_at(location).map(str::to_string)
     }
 }
 
@@ -67,11 +773,44 @@ impl MockLocationGenerator {
 }
 
 impl LocationGeneratorTrait for MockLocationGenerator {
-    fn sample_from_dataset(&self, _dataset: &str) -> Location {
-        Location {
+    fn sample_with_rng(
+        &self,
+        _dataset: &str,
+        _rng: &mut dyn RngCore,
+    ) -> Result<Location, SampleError> {
+        Ok(Location {
             latitude: 30.0,
             longitude: 98.0,
-        }
+        })
+    }
+
+    fn has_dataset(&self, _dataset: &str) -> bool {
+        true
+    }
+
+    /// Every location is the same one, so there's no spacing them out
+    fn sample_spaced(
+        &self,
+        dataset: &str,
+        count: usize,
+        _min_spacing: DistanceKm,
+        rng: &mut dyn RngCore,
+    ) -> Result<Vec<Location>, SampleError> {
+        (0..count)
+            .map(|_| self.sample_with_rng(dataset, rng))
+            .collect()
+    }
+
+    fn dataset_extent(&self, _dataset: &str) -> Option<DistanceKm> {
+        None
+    }
+
+    fn has_regions(&self, _dataset: &str) -> bool {
+        false
+    }
+
+    fn region_at(&self, _dataset: &str, _location: &Location) -> Option<String> {
+        None
     }
 }
 
@@ -84,17 +823,58 @@ impl LocationGenerator {
     pub fn mock() -> LocationGenerator {
         LocationGenerator::Mock(MockLocationGenerator::new())
     }
-
-    pub fn from_datafile(datasets: &[(&str, &str)]) -> LocationGenerator {
-        LocationGenerator::Datafile(DatafileLocationGenerator::new(datasets))
-    }
 }
 
 impl LocationGeneratorTrait for LocationGenerator {
-    fn sample_from_dataset(&self, dataset: &str) -> Location {
+    fn sample_with_rng(
+        &self,
+        dataset: &str,
+        rng: &mut dyn RngCore,
+    ) -> Result<Location, SampleError> {
+        match self {
+            LocationGenerator::Datafile(x) => x.sample_with_rng(dataset, rng),
+            LocationGenerator::Mock(x) => x.sample_with_rng(dataset, rng),
+        }
+    }
+
+    fn has_dataset(&self, dataset: &str) -> bool {
         match self {
-            LocationGenerator::Datafile(x) => x.sample_from_dataset(dataset),
-            LocationGenerator::Mock(x) => x.sample_from_dataset(dataset),
+            LocationGenerator::Datafile(x) => x.has_dataset(dataset),
+            LocationGenerator::Mock(x) => x.has_dataset(dataset),
+        }
+    }
+
+    fn sample_spaced(
+        &self,
+        dataset: &str,
+        count: usize,
+        min_spacing: DistanceKm,
+        rng: &mut dyn RngCore,
+    ) -> Result<Vec<Location>, SampleError> {
+        match self {
+            LocationGenerator::Datafile(x) => x.sample_spaced(dataset, count, min_spacing, rng),
+            LocationGenerator::Mock(x) => x.sample_spaced(dataset, count, min_spacing, rng),
+        }
+    }
+
+    fn dataset_extent(&self, dataset: &str) -> Option<DistanceKm> {
+        match self {
+            LocationGenerator::Datafile(x) => x.dataset_extent(dataset),
+            LocationGenerator::Mock(x) => x.dataset_extent(dataset),
+        }
+    }
+
+    fn has_regions(&self, dataset: &str) -> bool {
+        match self {
+            LocationGenerator::Datafile(x) => x.has_regions(dataset),
+            LocationGenerator::Mock(x) => x.has_regions(dataset),
+        }
+    }
+
+    fn region_at(&self, dataset: &str, location: &Location) -> Option<String> {
+        match self {
+            LocationGenerator::Datafile(x) => x.region_at(dataset, location),
+            LocationGenerator::Mock(x) => x.region_at(dataset, location),
         }
     }
 }
@@ -106,3 +886,458 @@ impl LocationGeneratorTrait for LocationGenerator {
         longitude: rng.gen::<f64>() - 98.,
     }
 }*/
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::datafile::test::TempDir;
+
+    fn contents(points: &[Location]) -> Vec<u8> {
+        let mut contents = vec![];
+        for point in points.iter() {
+            contents.extend_from_slice(&(point.latitude as f32).to_le_bytes());
+            contents.extend_from_slice(&(point.longitude as f32).to_le_bytes());
+        }
+        contents
+    }
+
+    fn dataset(points: &[Location]) -> Points {
+        let file = Dataset::from_bytes(&contents(points)).unwrap().1;
+        Points::new(Segment::split(file, None))
+    }
+
+    /// Loads places made of `files` (each named after its place), and then the places in
+    /// `composites`, in that order
+    fn load_places(files: &[(&str, Vec<u8>)], composites: &str) -> DatafileLocationGenerator {
+        let dir = TempDir::new("composites");
+        let mut config = String::new();
+        for (name, contents) in files.iter() {
+            let path = dir.file(&format!("{}.dat", name));
+            std::fs::write(&path, contents).unwrap();
+            config += &format!("{}: {{filename: {}}}\n", name, path);
+        }
+        config += composites;
+        let sources: Vec<(String, DatasetSource)> =
+            serde_yaml::from_str::<serde_yaml::Mapping>(&config)
+                .unwrap()
+                .into_iter()
+                .map(|(name, source)| {
+                    (
+                        name.as_str().unwrap().to_string(),
+                        serde_yaml::from_value(source).unwrap(),
+                    )
+                })
+                .collect();
+        let datasets: Vec<_> = sources
+            .iter()
+            .map(|(name, source)| (name.as_str(), source))
+            .collect();
+        DatafileLocationGenerator::new(&datasets, false)
+    }
+
+    #[test]
+    fn test_extent() {
+        assert!(bounding_box(std::iter::empty()).is_none());
+        let texas = [
+            Location {
+                latitude: 30.27,
+                longitude: -97.74,
+            },
+            Location {
+                latitude: 25.9,
+                longitude: -97.5,
+            },
+            Location {
+                latitude: 36.5,
+                longitude: -103.0,
+            },
+            Location {
+                latitude: 31.8,
+                longitude: -106.5,
+            },
+        ];
+        let (min, max) = bounding_box(texas.iter().cloned()).unwrap();
+        let extent = min.distance_to(&max);
+        assert!(extent > 1_300.0 && extent < 1_600.0, "{}", extent);
+        let extent = dataset(&texas).extent;
+        assert!(extent > 1_300.0 && extent < 1_600.0, "{}", extent);
+
+        // Fiji is on both sides of the antimeridian, but it's not the size of the planet
+        let fiji: Vec<_> = [
+            (-16.0, 178.0),
+            (-18.0, 179.9),
+            (-17.0, -179.9),
+            (-19.0, -178.5),
+        ]
+        .iter()
+        .map(|(latitude, longitude)| Location {
+            latitude: *latitude,
+            longitude: *longitude,
+        })
+        .collect();
+        let extent = dataset(&fiji).extent;
+        assert!(extent > 350.0 && extent < 500.0, "{}", extent);
+    }
+
+    #[test]
+    fn test_seeded_sampling() {
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha8Rng;
+
+        let points: Vec<_> = (0..1000)
+            .map(|i| Location {
+                latitude: (i % 180) as f64 - 90.0,
+                longitude: (i % 360) as f64 - 180.0,
+            })
+            .collect();
+        let mut gen = DatafileLocationGenerator::new(&[], false);
+        gen.datasets.insert("world".to_string(), dataset(&points));
+
+        let sample = |seed| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            (0..5)
+                .map(|_| {
+                    let location = gen.sample_with_rng("world", &mut rng).unwrap();
+                    (location.latitude, location.longitude)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sample(42), sample(42));
+        assert_ne!(sample(42), sample(43));
+
+        // Seeds from before there was spacing still pick the same locations
+        let mut rng = ChaCha8Rng::seed_from_u64(42);
+        let old: Vec<_> = (0..5)
+            .map(|_| {
+                let idx: usize = rng.gen();
+                let location = &points[idx % points.len()];
+                (location.latitude, location.longitude)
+            })
+            .collect();
+        let mut rng = ChaCha8Rng::seed_from_u64(42);
+        let spaced: Vec<_> = gen
+            .sample_spaced("world", 5, 0.0, &mut rng)
+            .unwrap()
+            .iter()
+            .map(|location| (location.latitude, location.longitude))
+            .collect();
+        assert_eq!(spaced, old);
+    }
+
+    #[test]
+    fn test_all_from() {
+        let points: Vec<_> = (0..5)
+            .map(|i| Location {
+                latitude: 0.0,
+                longitude: i as f64,
+            })
+            .collect();
+        let mut segments = dataset(&points[..2]).segments;
+        segments.extend(dataset(&points[2..]).segments);
+        let points = Points::new(segments);
+        let ids: Vec<_> = points.all_from(3).collect();
+        assert_eq!(ids, vec![(1, 1), (1, 2), (0, 0), (0, 1), (1, 0)]);
+        assert_eq!(points.all_from(0).count(), 5);
+    }
+
+    #[test]
+    fn test_spaced_sampling() {
+        // Ten points along the equator, about 111km apart
+        let points: Vec<_> = (0..10)
+            .map(|i| Location {
+                latitude: 0.0,
+                longitude: i as f64,
+            })
+            .collect();
+        let mut gen = DatafileLocationGenerator::new(&[], false);
+        gen.datasets.insert("line".to_string(), dataset(&points));
+        let mut rng = rand::thread_rng();
+
+        let all = gen.sample_spaced("line", 10, 0.0, &mut rng).unwrap();
+        let mut longitudes: Vec<_> = all.iter().map(|l| l.longitude as i32).collect();
+        longitudes.sort_unstable();
+        assert_eq!(longitudes, (0..10).collect::<Vec<_>>());
+        assert_eq!(
+            gen.sample_spaced("line", 11, 0.0, &mut rng).unwrap_err(),
+            SampleError::NotEnoughLocations {
+                wanted: 11,
+                found: 10,
+                min_spacing: 0.0
+            }
+        );
+
+        // However the first few get picked, there's always room for four of them
+        for _ in 0..20 {
+            let spaced = gen.sample_spaced("line", 4, 200.0, &mut rng).unwrap();
+            for (i, a) in spaced.iter().enumerate() {
+                for b in spaced[i + 1..].iter() {
+                    assert!(a.distance_to(b) >= 200.0);
+                }
+            }
+        }
+        assert!(gen.sample_spaced("line", 6, 200.0, &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_loading() {
+        let dir = TempDir::new("datasets");
+        let path = |name: &str| dir.file(name);
+        let mut good = vec![];
+        for value in [10.0f32, 20.0, -30.0, 40.0].iter() {
+            good.extend_from_slice(&value.to_le_bytes());
+        }
+        std::fs::write(path("good.dat"), &good).unwrap();
+        std::fs::write(path("truncated.dat"), &good[..13]).unwrap();
+        let headered = crate::datafile::test::write(&[(1.0, 2.0)], "planet.osm.pbf");
+        std::fs::write(path("headered.dat"), &headered).unwrap();
+        std::fs::write(path("cut-short.dat"), &headered[..headered.len() - 3]).unwrap();
+        std::fs::write(path("empty.dat"), b"").unwrap();
+
+        let datasets = [
+            ("good", path("good.dat")),
+            ("truncated", path("truncated.dat")),
+            ("empty", path("empty.dat")),
+            ("missing", path("missing.dat")),
+            ("headered", path("headered.dat")),
+            ("cut-short", path("cut-short.dat")),
+        ];
+        let sources: Vec<_> = datasets
+            .iter()
+            .map(|(_, file)| DatasetSource::file(file))
+            .collect();
+        let datasets: Vec<_> = datasets
+            .iter()
+            .zip(sources.iter())
+            .map(|((name, _), source)| (*name, source))
+            .collect();
+        let gen = DatafileLocationGenerator::new(&datasets, true);
+
+        let report = gen.report();
+        assert_eq!(report.len(), 6);
+        assert_eq!(report[0].result.as_ref().unwrap(), &2);
+        assert!(matches!(
+            report[1].result,
+            Err(LoadError::Truncated { len: 13 })
+        ));
+        assert!(matches!(report[2].result, Err(LoadError::Empty)));
+        assert!(matches!(report[3].result, Err(LoadError::Io(_))));
+        assert!(report[3].to_string().starts_with("missing ("));
+        assert!(report[0].header.is_none());
+        assert_eq!(report[4].result.as_ref().unwrap(), &1);
+        assert_eq!(report[4].header.as_ref().unwrap().source, "planet.osm.pbf");
+        assert!(matches!(
+            report[5].result,
+            Err(LoadError::WrongCount {
+                expected: 1,
+                found: 0
+            })
+        ));
+
+        assert!(gen.has_dataset("good"));
+        assert_eq!(gen.sample_from_dataset("headered").unwrap().longitude, 2.0);
+        assert!(!gen.has_dataset("missing"));
+        let location = gen.sample_from_dataset("good").unwrap();
+        assert!(location.latitude == 10.0 || location.latitude == -30.0);
+        let mut rng = rand::thread_rng();
+        assert_eq!(
+            gen.sample_spaced("world", 1, 0.0, &mut rng).unwrap_err(),
+            SampleError::UnknownDataset("world".to_string())
+        );
+        assert!(gen.sample_from_dataset("empty").is_err());
+    }
+
+    #[test]
+    fn test_composites() {
+        // Lots of Europe, not much of the US
+        let europe: Vec<_> = (0..90)
+            .map(|i| Location {
+                latitude: 40.0 + (i / 10) as f64,
+                longitude: (i % 10) as f64,
+            })
+            .collect();
+        let us: Vec<_> = (0..10)
+            .map(|i| Location {
+                latitude: 40.0,
+                longitude: -100.0 + i as f64,
+            })
+            .collect();
+        let gen = load_places(
+            &[("europe", contents(&europe)), ("us", contents(&us))],
+            "half: {composite: {type: mix, parts: [{place: europe, weight: 1}, {place: us, weight: 1}]}}\n\
+             both: {composite: {type: union, places: [europe, us]}}\n\
+             north: {composite: {type: within, place: both, bbox: {min_latitude: 45, min_longitude: -180, max_latitude: 90, max_longitude: 180}}}\n\
+             nowhere: {composite: {type: within, place: us, polygon: [[0, 0], [1, 0], [0, 1]]}}\n\
+             broken: {composite: {type: union, places: [us, mars]}}\n\
+             circle: {composite: {type: union, places: [us, loop]}}\n\
+             loop: {composite: {type: union, places: [circle]}}\n\
+             unweighted: {composite: {type: mix, parts: [{place: us, weight: 0}]}}\n",
+        );
+
+        let counts: Vec<_> = gen
+            .report()
+            .iter()
+            .map(|report| report.result.as_ref().ok().copied())
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                Some(90),
+                Some(10),
+                Some(100),
+                Some(100),
+                Some(40),
+                None,
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+        let errors: Vec<_> = gen.report()[5..]
+            .iter()
+            .map(|report| report.result.as_ref().unwrap_err().to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "it has no locations in it",
+                "mars is not available",
+                "circle ends up being made from itself",
+                "circle ends up being made from itself",
+                "the weights in a mix must be above zero"
+            ]
+        );
+
+        let mut rng = rand::thread_rng();
+        let in_us = |dataset| {
+            (0..1000)
+                .filter(|_| {
+                    gen.sample_with_rng(dataset, &mut rand::thread_rng())
+                        .unwrap()
+                        .longitude
+                        < 0.0
+                })
+                .count()
+        };
+        // Half and half, even though Europe is much bigger
+        assert!((350..650).contains(&in_us("half")), "{}", in_us("half"));
+        // Every location equally likely, so not many from the US
+        assert!(in_us("both") < 250, "{}", in_us("both"));
+        assert_eq!(in_us("north"), 0);
+
+        let north = gen.sample_spaced("north", 40, 0.0, &mut rng).unwrap();
+        assert!(north.iter().all(|l| l.latitude >= 45.0));
+        assert!(gen.sample_spaced("north", 41, 0.0, &mut rng).is_err());
+        let extent = gen.dataset_extent("north").unwrap();
+        assert!(extent < gen.dataset_extent("both").unwrap());
+    }
+
+    #[test]
+    fn test_balanced() {
+        // Lots of Texas, some California, a little France, and a few points out at sea
+        let mut points = vec![(31.0, -100.0); 60];
+        points.extend(vec![(37.0, -120.0); 30]);
+        points.extend(vec![(47.0, 2.0); 10]);
+        points.extend(vec![(0.0, -30.0); 5]);
+        let regions = [("US-TX", 60), ("US-CA", 30), ("FR", 10), ("", 5)];
+        let old = contents(&[Location {
+            latitude: 1.0,
+            longitude: 2.0,
+        }]);
+        let gen = load_places(
+            &[
+                (
+                    "world",
+                    crate::datafile::test::write_regions(&regions, &points, "planet.osm.pbf"),
+                ),
+                ("old", old),
+            ],
+            "countries: {composite: {type: balanced, place: world}}\n\
+             regions: {composite: {type: balanced, place: world, by: region}}\n\
+             france: {composite: {type: balanced, place: world, weights: {US: 0}}}\n\
+             unbalanced: {composite: {type: balanced, place: old}}\n\
+             negative: {composite: {type: balanced, place: world, weights: {FR: -1}}}\n",
+        );
+
+        let report = gen.report();
+        assert!(report[0].to_string().ends_with(", in 4 regions"));
+        assert_eq!(report[1].result.as_ref().unwrap(), &1);
+        assert_eq!(report[2].result.as_ref().unwrap(), &100);
+        assert_eq!(report[4].result.as_ref().unwrap(), &10);
+        assert!(matches!(report[5].result, Err(LoadError::NoRegions)));
+        assert!(matches!(report[6].result, Err(LoadError::BadWeights)));
+
+        let in_france = |dataset| {
+            (0..1000)
+                .filter(|_| {
+                    gen.sample_with_rng(dataset, &mut rand::thread_rng())
+                        .unwrap()
+                        .longitude
+                        > 0.0
+                })
+                .count()
+        };
+        // About a tenth of the points, but half of the countries, or a third of the regions
+        assert!(in_france("world") < 200, "{}", in_france("world"));
+        assert!(
+            (400..600).contains(&in_france("countries")),
+            "{}",
+            in_france("countries")
+        );
+        assert!(
+            (250..420).contains(&in_france("regions")),
+            "{}",
+            in_france("regions")
+        );
+        assert_eq!(in_france("france"), 1000);
+        let mut rng = rand::thread_rng();
+        assert!(gen.sample_spaced("france", 11, 0.0, &mut rng).is_err());
+
+        let at = |latitude, longitude| Location {
+            latitude,
+            longitude,
+        };
+        assert!(gen.has_regions("world"));
+        assert!(gen.has_regions("countries"));
+        assert!(!gen.has_regions("old"));
+        // Composites know where they are from whatever they're made of
+        assert_eq!(
+            gen.region_at("countries", &at(31.0, -100.0)).unwrap(),
+            "US-TX"
+        );
+        assert_eq!(gen.region_at("france", &at(47.05, 2.05)).unwrap(), "FR");
+        assert_eq!(
+            gen.region_at("world", &at(37.05, -119.95)).unwrap(),
+            "US-CA"
+        );
+        // Out at sea, and nowhere near anything
+        assert!(gen.region_at("world", &at(0.0, -30.0)).is_none());
+        assert!(gen.region_at("world", &at(-60.0, 100.0)).is_none());
+        // Just over the border from the points, which might well be somewhere else
+        assert!(gen.region_at("world", &at(30.8, -100.0)).is_none());
+        assert!(gen.region_at("old", &at(1.0, 2.0)).is_none());
+    }
+
+    #[test]
+    fn test_border() {
+        // El Paso, in a file of only the US
+        let points = [(31.76, -106.49), (31.79, -106.42), (31.85, -106.44)];
+        let gen = load_places(
+            &[(
+                "texas",
+                crate::datafile::test::write_regions(&[("US-TX", 3)], &points, "x"),
+            )],
+            "",
+        );
+        let at = |latitude, longitude| Location {
+            latitude,
+            longitude,
+        };
+        assert_eq!(
+            gen.region_at("texas", &at(31.78, -106.45)).unwrap(),
+            "US-TX"
+        );
+        // Ciudad Juárez, just across the Rio Grande
+        assert!(gen.region_at("texas", &at(31.69, -106.42)).is_none());
+    }
+}
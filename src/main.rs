@@ -3,53 +3,268 @@
 #[macro_use]
 extern crate rocket;
 
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rocket::http::uri::{Origin, Uri};
 use rocket::http::Status;
 use rocket::http::{Cookie, Cookies};
 use rocket::request::Form;
 use rocket::request::FromForm;
 use rocket::request::FromRequest;
 use rocket::request::Outcome;
+use rocket::response::status;
+use rocket::response::Redirect;
+use rocket::response::{self, Responder};
 use rocket::Request;
 use rocket::State;
+use rocket_contrib::json::Json;
 use rocket_contrib::templates::Template;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::Weak;
+use std::time::{Duration, Instant};
 
+mod api;
+mod catalog;
+mod composite;
+mod daily;
+mod datafile;
+mod events;
 mod game_logic;
 mod location;
 mod location_generator;
+mod panorama;
+mod scoring;
+mod settings;
+mod storage;
 
+use catalog::*;
+use composite::DatasetSource;
+use daily::*;
+use events::*;
 use game_logic::*;
 use location::*;
 use location_generator::*;
+use panorama::*;
+use settings::*;
+use storage::*;
 
 type PlayerId = usize;
 type GameId = usize;
 
+const JOIN_CODE_LENGTH: usize = 5;
+
+/// Long enough that nobody is going to guess somebody else's
+const SESSION_TOKEN_LENGTH: usize = 32;
+
+/// Name of the (private) cookie holding the session token
+const SESSION_COOKIE: &str = "session";
+
 struct GuardedGame(Arc<Mutex<Game>>);
 struct GuardedGameAndPid(Arc<Mutex<Game>>, PlayerId);
 
+/// Keeps the server from filling up with games
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+struct Limits {
+    /// Past this, nobody can create a new game until some old ones get cleaned up
+    max_games: usize,
+
+    /// Seconds to keep a finished game around, so the players can look over their results
+    finished_retention: u64,
+
+    /// Seconds after which a game nobody is playing is thrown out
+    idle_timeout: u64,
+
+    /// How many event streams can be open at once, each of which ties up a worker. By
+    /// default, half of Rocket's workers.
+    max_event_streams: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_games: 1000,
+            finished_retention: 60 * 60,
+            idle_timeout: 24 * 60 * 60,
+            max_event_streams: None,
+        }
+    }
+}
+
 struct Games {
-    generator: LocationGenerator,
+    storage: GameStorage,
+    limits: Limits,
     games: HashMap<GameId, GuardedGame>,
     players: HashMap<PlayerId, GameId>,
+    sessions: HashMap<String, PlayerId>,
+    join_codes: HashMap<String, GameId>,
     next_playerid: usize,
     next_gameid: usize,
+    leaderboard: Leaderboard,
 }
 
 type GuardedGames = Arc<Mutex<Games>>;
 
+enum JoinError {
+    UnknownCode,
+    Game(Error),
+}
+
+impl JoinError {
+    fn message(&self) -> &'static str {
+        match self {
+            JoinError::UnknownCode => "There is no game with that join code.",
+            JoinError::Game(Error::CannotAddPlayer) => "That game has already started.",
+            JoinError::Game(_) => "Could not join that game.",
+        }
+    }
+}
+
+enum CreateError {
+    Settings(SettingsError),
+    TooManyGames,
+
+    /// The place can't provide enough locations with the settings asked for
+    Locations(SampleError),
+}
+
+impl CreateError {
+    fn message(&self) -> String {
+        match self {
+            CreateError::Settings(e) => e.to_string(),
+            CreateError::TooManyGames => {
+                "There are too many games going on right now. Try again later!".to_string()
+            }
+            CreateError::Locations(e) => e.to_string(),
+        }
+    }
+}
+
+impl From<SettingsError> for CreateError {
+    fn from(e: SettingsError) -> CreateError {
+        CreateError::Settings(e)
+    }
+}
+
+/// Why a player can't play a daily challenge
+enum DailyError {
+    /// There's no daily challenge for that place
+    NoChallenge,
+
+    /// Only one go per day
+    AlreadyPlayed,
+
+    Create(CreateError),
+}
+
+impl DailyError {
+    fn message(&self) -> String {
+        match self {
+            DailyError::NoChallenge => "There is no daily challenge for that place.".to_string(),
+            DailyError::AlreadyPlayed => {
+                "You've already played today's challenge. Come back tomorrow!".to_string()
+            }
+            DailyError::Create(e) => e.message(),
+        }
+    }
+}
+
+/// The status code for a player trying something the game won't let them do
+fn error_status(e: &Error) -> Status {
+    match e {
+        Error::UnknownPlayer(_) => Status::NotFound,
+        Error::CannotAddPlayer
+        | Error::AlreadyStarted
+        | Error::WaitingForPlayers
+        | Error::AlreadyGuessed
+        | Error::WrongState(_)
+        | Error::GameOver => Status::Conflict,
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorContext {
+    message: String,
+}
+
+/// A friendly page explaining what went wrong, with a way back into the game
+#[derive(Debug)]
+struct ErrorPage {
+    status: Status,
+    message: String,
+}
+
+impl From<Error> for ErrorPage {
+    fn from(e: Error) -> ErrorPage {
+        ErrorPage {
+            status: error_status(&e),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ErrorPage {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let context = ErrorContext {
+            message: self.message,
+        };
+        status::Custom(self.status, Template::render("error", context)).respond_to(request)
+    }
+}
+
+/// Why a request didn't come from a player in a game
+#[derive(Debug)]
+enum SessionError {
+    /// No session cookie, or one that we didn't make
+    Missing,
+
+    /// The session was real, but its game is gone
+    Expired,
+}
+
 impl Games {
-    fn new(generator: LocationGenerator) -> Games {
-        Games {
-            generator: generator,
+    /// Picks up any games which were left in the storage
+    fn new(storage: GameStorage, limits: Limits) -> Games {
+        let leaderboard = storage.load_leaderboard().unwrap_or_default();
+        let mut games = Games {
+            storage,
+            limits,
             games: HashMap::new(),
             players: HashMap::new(),
+            sessions: HashMap::new(),
+            join_codes: HashMap::new(),
             next_playerid: 0,
-            next_gameid: 0,
+            next_gameid: leaderboard.last_game(),
+            leaderboard,
+        };
+        for (gameid, mut game) in games.storage.load() {
+            game.resume();
+            for pid in game.get_player_ids() {
+                games.players.insert(pid, gameid);
+                games.next_playerid = games.next_playerid.max(pid);
+            }
+            for (token, pid) in game.get_sessions() {
+                games.sessions.insert(token.clone(), *pid);
+            }
+            games
+                .join_codes
+                .insert(game.get_join_code().to_string(), gameid);
+            games.next_gameid = games.next_gameid.max(gameid);
+            games
+                .games
+                .insert(gameid, GuardedGame(Arc::new(Mutex::new(game))));
         }
+        if !games.games.is_empty() {
+            log::info!("Loaded {} saved games", games.games.len());
+        }
+        games
+    }
+
+    fn is_full(&self) -> bool {
+        self.games.len() >= self.limits.max_games
     }
 
     fn new_playerid(&mut self) -> usize {
@@ -57,46 +272,266 @@ impl Games {
         self.next_playerid
     }
 
-    fn add_game(&mut self, game: Game) {
+    /// Generate a join code which isn't in use by any other game
+    fn new_join_code(&self) -> String {
+        // Leave out characters which are easily confused with each other (0/O, 1/I)
+        const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+        let mut rng = rand::thread_rng();
+        loop {
+            let code: String = (0..JOIN_CODE_LENGTH)
+                .map(|_| ALPHABET[rng.gen_range(0, ALPHABET.len())] as char)
+                .collect();
+            if !self.join_codes.contains_key(&code) {
+                return code;
+            }
+        }
+    }
+
+    /// Picks the locations for a new game. That can take a while, so it's done before
+    /// taking the lock on the games.
+    fn new_game(
+        mut settings: GameSettings,
+        generator: &LocationGenerator,
+    ) -> Result<Game, CreateError> {
+        let extent = generator.dataset_extent(&settings.dataset);
+        if let Some(extent) = extent {
+            // Any further apart and there's no hope of fitting them all in
+            let max_spacing = extent / settings.num_rounds.max(1) as DistanceKm;
+            if settings.min_spacing_km > max_spacing {
+                return Err(SettingsError::SpacingTooBig(max_spacing).into());
+            }
+        }
+        if settings.scoring_extent.is_none() {
+            settings.scoring_extent = extent;
+        }
+        Game::new(settings, generator).map_err(CreateError::Locations)
+    }
+
+    fn create_game(&mut self, game: Game, nickname: &str) -> Result<PlayerId, CreateError> {
+        if self.is_full() {
+            return Err(CreateError::TooManyGames);
+        }
+        let mut game = game;
+        let playerid = self.new_playerid();
+        game.add_player(playerid, nickname).unwrap();
+        self.add_game(game);
+        Ok(playerid)
+    }
+
+    fn add_game(&mut self, mut game: Game) -> GameId {
         self.next_gameid += 1;
+        let join_code = self.new_join_code();
+        game.set_join_code(&join_code);
+        self.join_codes.insert(join_code, self.next_gameid);
         for pid in game.get_player_ids().iter() {
             self.players.insert(*pid, self.next_gameid);
         }
         self.games
             .insert(self.next_gameid, GuardedGame(Arc::new(Mutex::new(game))));
+        self.next_gameid
+    }
+
+    /// Add a new player to the game with the given join code
+    fn join_game(&mut self, join_code: &str, nickname: &str) -> Result<PlayerId, JoinError> {
+        let gameid = *self
+            .join_codes
+            .get(&join_code.trim().to_uppercase())
+            .ok_or(JoinError::UnknownCode)?;
+        let game = self.games.get(&gameid).ok_or(JoinError::UnknownCode)?;
+        // The id is only used up once the player has made it into the game
+        let playerid = self.next_playerid + 1;
+        game.0
+            .lock()
+            .unwrap()
+            .add_player(playerid, nickname)
+            .map_err(JoinError::Game)?;
+        self.next_playerid = playerid;
+        self.players.insert(playerid, gameid);
+        Ok(playerid)
+    }
+
+    /// Hand out a session token for a player who is in a game, which is how they prove who they are
+    fn new_session(&mut self, playerid: PlayerId) -> String {
+        let mut rng = rand::thread_rng();
+        let token = loop {
+            let token: String = (&mut rng)
+                .sample_iter(&Alphanumeric)
+                .take(SESSION_TOKEN_LENGTH)
+                .collect();
+            if !self.sessions.contains_key(&token) {
+                break token;
+            }
+        };
+        if let Some(game) = self.get_game(playerid) {
+            game.0.lock().unwrap().add_session(&token, playerid);
+        }
+        self.sessions.insert(token.clone(), playerid);
+        token
+    }
+
+    /// Throw out a game, along with everything that points to it
+    fn remove_game(&mut self, gameid: GameId) {
+        self.games.remove(&gameid);
+        self.players.retain(|_, g| *g != gameid);
+        let players = &self.players;
+        self.sessions.retain(|_, pid| players.contains_key(pid));
+        self.join_codes.retain(|_, g| *g != gameid);
+        self.storage.delete_game(gameid);
+    }
+
+    fn get_player(&self, token: &str) -> Option<PlayerId> {
+        self.sessions.get(token).copied()
     }
 
     fn get_game(&mut self, playerid: usize) -> Option<GuardedGame> {
         let gameid = self.players.get(&playerid)?;
-        let game = self.games.get_mut(&gameid)?;
+        let game = self.games.get_mut(gameid)?;
         Some(GuardedGame(game.0.clone()))
     }
 }
 
 impl<'a, 'r> FromRequest<'a, 'r> for GuardedGameAndPid {
-    type Error = ();
+    type Error = SessionError;
 
     fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
-        let playerid = match request.cookies().get("playerid") {
-            Some(s) => match s.value().parse::<usize>() {
-                Ok(pid) => pid,
-                Err(_) => {
-                    return Outcome::Failure((Status::BadRequest, ()));
-                }
-            },
+        // Private cookies are encrypted, so a forged or tampered cookie looks the same as none at all
+        let token = match request.cookies().get_private(SESSION_COOKIE) {
+            Some(cookie) => cookie.value().to_string(),
             None => {
-                return Outcome::Failure((Status::BadRequest, ()));
+                return Outcome::Failure((Status::Unauthorized, SessionError::Missing));
             }
         };
-        log::info!("Received authentication from playerid={}", playerid);
 
         let db = request.guard::<State<GuardedGames>>().unwrap();
         let mut db = db.inner().lock().unwrap();
+        let playerid = match db.get_player(&token) {
+            Some(pid) => pid,
+            None => {
+                return Outcome::Failure((Status::Unauthorized, SessionError::Expired));
+            }
+        };
+        log::info!("Received authentication from playerid={}", playerid);
+
         match db.get_game(playerid) {
-            Some(game) => Outcome::Success(GuardedGameAndPid(game.0, playerid)),
-            None => Outcome::Failure((Status::BadRequest, ())),
+            Some(game) => {
+                // Don't make the player wait for the ticker to notice a timeout
+                let now = Instant::now();
+                let mut locked = game.0.lock().unwrap();
+                locked.tick(now);
+                locked.touch(now);
+                drop(locked);
+                Outcome::Success(GuardedGameAndPid(game.0, playerid))
+            }
+            None => Outcome::Failure((Status::Unauthorized, SessionError::Expired)),
+        }
+    }
+}
+
+/// Enforce the time limits of every game, and save the games which have changed
+fn tick_games(db: &GuardedGames) {
+    let games: Vec<_> = db
+        .lock()
+        .unwrap()
+        .games
+        .iter()
+        .map(|(gameid, game)| (*gameid, game.0.clone()))
+        .collect();
+    let now = Instant::now();
+    let mut changed = vec![];
+    let mut daily_scores = vec![];
+    for (gameid, game) in games.iter() {
+        let mut game = game.lock().unwrap();
+        game.tick(now);
+        if game.take_dirty() {
+            changed.push((*gameid, serde_json::to_string(&*game).unwrap()));
+            daily_scores.extend(finished_daily_scores(*gameid, &game));
         }
     }
+    // Only take the lock on the games once we're done with each individual game,
+    // since requests lock them in the other order
+    let mut db = db.lock().unwrap();
+    for (gameid, serialized) in changed.drain(..) {
+        db.storage.save_game(gameid, serialized);
+    }
+    let mut recorded = false;
+    for (place, day, score) in daily_scores {
+        recorded |= db.leaderboard.record(&place, day, score);
+    }
+    if recorded {
+        let serialized = serde_json::to_string(&db.leaderboard).unwrap();
+        db.storage.save_leaderboard(serialized);
+    }
+}
+
+/// What goes on the leaderboard, once a daily challenge is over
+fn finished_daily_scores(gameid: GameId, game: &Game) -> Vec<(String, Day, DailyScore)> {
+    let settings = game.get_settings();
+    let day = match settings.daily {
+        Some(day) if game.is_finished() => day,
+        _ => return vec![],
+    };
+    game.get_player_ids()
+        .into_iter()
+        .filter_map(|pid| game.get_player(pid))
+        .map(|player| {
+            let score = DailyScore {
+                name: player.name.clone(),
+                score: player.points,
+                game: gameid,
+            };
+            (settings.dataset.clone(), day, score)
+        })
+        .collect()
+}
+
+/// Clean out games which are finished or which everyone has wandered away from
+fn reap_games(db: &GuardedGames, now: Instant) {
+    let mut db = db.lock().unwrap();
+    let finished_retention = Duration::from_secs(db.limits.finished_retention);
+    let idle_timeout = Duration::from_secs(db.limits.idle_timeout);
+    let expired: Vec<GameId> = db
+        .games
+        .iter()
+        .filter(|(_, game)| {
+            let game = game.0.lock().unwrap();
+            let idle = game.idle_time(now);
+            (game.is_finished() && idle >= finished_retention) || idle >= idle_timeout
+        })
+        .map(|(gameid, _)| *gameid)
+        .collect();
+    for gameid in expired {
+        log::info!("Removing game {}", gameid);
+        db.remove_game(gameid);
+    }
+}
+
+/// Keeps ticking the games for as long as they're around, which is until the server they
+/// belong to is dropped
+fn spawn_ticker(db: Weak<Mutex<Games>>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let db = match db.upgrade() {
+            Some(db) => db,
+            None => break,
+        };
+        tick_games(&db);
+        reap_games(&db, Instant::now());
+    });
+}
+
+/// Give the player their session, replacing any they had from an earlier game
+fn set_session(cookies: &mut Cookies, token: String) {
+    cookies.add_private(Cookie::new(SESSION_COOKIE, token));
+}
+
+#[catch(401)]
+fn unauthorized(request: &Request) -> Template {
+    let places = request.guard::<Places>().unwrap();
+    render_index(
+        &places,
+        None,
+        Some("You aren't in a game, or your game has ended. Create or join a new one!"),
+    )
 }
 
 #[get("/hello/<name>/<age>")]
@@ -107,55 +542,336 @@ fn hello(name: String, age: u8) -> String {
 #[derive(Serialize)]
 struct IndexContext<'a> {
     places: &'a [PlaceSpec],
+    join_code: Option<String>,
+    error: Option<&'a str>,
 }
 
-fn render_index(places: &[PlaceSpec]) -> Template {
-    let context = IndexContext { places };
-    Template::render("index", &context)
+fn render_index(places: &[PlaceSpec], join_code: Option<String>, error: Option<&str>) -> Template {
+    let context = IndexContext {
+        places,
+        join_code,
+        error,
+    };
+    Template::render("index", context)
 }
 
-#[get("/index")]
-fn index(places: State<Vec<PlaceSpec>>) -> Template {
-    let data: HashMap<String, String> = HashMap::new();
-    render_index(&places)
+#[get("/index?<code>")]
+fn index(places: Places, code: Option<String>) -> Template {
+    render_index(&places, code, None)
 }
 
 #[get("/")]
-fn root(places: State<Vec<PlaceSpec>>) -> Template {
-    let data: HashMap<String, String> = HashMap::new();
-    render_index(&places)
+fn root(places: Places) -> Template {
+    render_index(&places, None, None)
 }
 
-#[derive(FromForm)]
+#[derive(FromForm, Deserialize)]
 struct CreateGame {
     place: String,
+    nickname: String,
+    rounds: Option<usize>,
+    round_time: Option<u64>,
+    results_time: Option<u64>,
+    scoring: Option<String>,
+    movement: Option<String>,
+    advance: Option<String>,
+
+    /// In km, blank for the place's default
+    min_spacing: Option<DistanceKm>,
+
+    /// To play the same locations as somebody else
+    seed: Option<u64>,
+}
+
+/// Time limits come in as a number of seconds, where blank or zero means no limit
+fn time_limit(seconds: Option<u64>) -> Option<u64> {
+    seconds.filter(|s| *s > 0)
+}
+
+impl CreateGame {
+    /// Anything left blank gets the default
+    fn settings(&self, places: &[PlaceSpec]) -> Result<GameSettings, SettingsError> {
+        let mut settings = GameSettings::new(&self.place);
+        if let Some(rounds) = self.rounds {
+            settings.num_rounds = rounds;
+        }
+        settings.round_time_limit = time_limit(self.round_time);
+        settings.results_time_limit = time_limit(self.results_time);
+        if let Some(scoring) = &self.scoring {
+            settings.scoring = scoring.parse()?;
+        }
+        if let Some(movement) = &self.movement {
+            settings.movement = movement.parse()?;
+        }
+        if let Some(advance) = &self.advance {
+            settings.advance = advance.parse()?;
+        }
+        settings.seed = self.seed;
+        let place = places.iter().find(|p| p.key == settings.dataset);
+        settings.min_spacing_km = self
+            .min_spacing
+            .or_else(|| place.and_then(|p| p.min_spacing_km))
+            .unwrap_or(0.0);
+        settings.validate(places.iter().map(|p| p.key.as_str()))?;
+        settings.scoring_extent = place.and_then(|p| p.scoring_extent_km);
+        Ok(settings)
+    }
+}
+
+/// The name a setting goes by in forms, which is the same as its name in JSON
+fn setting_name(setting: impl serde::Serialize) -> String {
+    serde_json::to_value(setting)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Everything needed to play the same locations as somebody else, as passed around in
+/// challenge links
+#[derive(FromForm, Serialize)]
+struct Challenge {
+    place: String,
+    rounds: usize,
+    round_time: Option<u64>,
+    scoring: String,
+    movement: String,
+
+    /// None in links from before games could be spaced out
+    min_spacing: Option<DistanceKm>,
+    seed: u64,
+}
+
+impl Challenge {
+    /// None for games from before games had seeds, and for daily challenges (since
+    /// everyone only gets one go at those)
+    fn new(settings: &GameSettings) -> Option<Challenge> {
+        if settings.daily.is_some() {
+            return None;
+        }
+        Some(Challenge {
+            place: settings.dataset.clone(),
+            rounds: settings.num_rounds,
+            round_time: settings.round_time_limit,
+            scoring: setting_name(settings.scoring),
+            movement: setting_name(settings.movement),
+            min_spacing: Some(settings.min_spacing_km),
+            seed: settings.seed?,
+        })
+    }
+
+    /// Relative to the root, like every other link
+    fn link(&self) -> String {
+        format!(
+            "challenge?place={}&rounds={}&round_time={}&scoring={}&movement={}&min_spacing={}&seed={}",
+            Uri::percent_encode(&self.place),
+            self.rounds,
+            self.round_time.map(|t| t.to_string()).unwrap_or_default(),
+            self.scoring,
+            self.movement,
+            self.min_spacing.map(|km| km.to_string()).unwrap_or_default(),
+            self.seed
+        )
+    }
+
+    fn create_game(&self) -> CreateGame {
+        CreateGame {
+            place: self.place.clone(),
+            nickname: String::new(),
+            rounds: Some(self.rounds),
+            round_time: self.round_time,
+            results_time: None,
+            scoring: Some(self.scoring.clone()),
+            movement: Some(self.movement.clone()),
+            advance: None,
+            // Links from before there was spacing get the same locations as they did then,
+            // unless their seed happened to pick the same location twice
+            min_spacing: Some(self.min_spacing.unwrap_or(0.0)),
+            seed: Some(self.seed),
+        }
+    }
+}
+
+fn seconds_remaining(game: &Game) -> Option<u64> {
+    game.get_time_remaining(Instant::now())
+        .map(|remaining| remaining.as_secs())
+}
+
+#[derive(FromForm, Deserialize)]
+struct JoinGame {
+    code: String,
+    nickname: String,
+}
+
+/// Players who don't bother to pick a name are all just "Player"
+fn nickname_or_default(nickname: &str) -> &str {
+    let nickname = nickname.trim();
+    if nickname.is_empty() {
+        "Player"
+    } else {
+        nickname
+    }
 }
 
 #[derive(Deserialize, Serialize)]
-struct PlaceSpec {
+pub struct PlaceSpec {
     key: String,
-    filename: String,
+
+    /// A file of its own, or other places put together
+    #[serde(flatten)]
+    source: DatasetSource,
     human_name: String,
+
+    /// Overrides how far apart the place's locations are, for scoring
+    scoring_extent_km: Option<DistanceKm>,
+
+    /// Whether there's a daily challenge for the place
+    #[serde(default)]
+    daily: bool,
+
+    /// How far apart the locations in a game are, unless the host says otherwise
+    min_spacing_km: Option<DistanceKm>,
+}
+
+/// Everything the players get to know about the location they're guessing
+#[derive(Serialize)]
+struct RoundContext {
+    pano_id: Option<String>,
+    locations_remaining: usize,
+    seconds_remaining: Option<u64>,
+    round: usize,
+    num_rounds: usize,
+    movement: MovementRule,
 }
 
 #[derive(Serialize)]
 struct PlayGameContext {
     api_key: String,
-    location: Location,
-    locations_remaining: usize,
+    #[serde(flatten)]
+    round: RoundContext,
+}
+
+/// The round being played
+fn current_round(game: &Game) -> RoundContext {
+    RoundContext {
+        pano_id: game.get_current_panorama().map(|p| p.pano_id.clone()),
+        locations_remaining: game.get_locations_remaining(),
+        seconds_remaining: seconds_remaining(game),
+        round: game.get_round(),
+        num_rounds: game.get_settings().num_rounds,
+        movement: game.get_settings().movement,
+    }
+}
+
+/// Find the panorama for the round the player is guessing if nobody has needed it yet,
+/// letting go of the game while we wait on the resolver
+fn resolve_current_panorama(resolver: &PanoramaResolver, game: &Mutex<Game>, playerid: PlayerId) {
+    let (round, location) = {
+        let game = game.lock().unwrap();
+        let guessing = game
+            .get_player(playerid)
+            .map_or(false, |player| player.state == PlayerState::Guessing);
+        if !guessing || game.get_current_panorama().is_some() {
+            return;
+        }
+        (game.get_round(), game.get_current_location())
+    };
+    if let Some(panorama) = resolve_pano_id(resolver, &location) {
+        let mut game = game.lock().unwrap();
+        // Somebody else may have got there first, or the game moved on while we waited
+        if game.get_round() == round && game.get_current_panorama().is_none() {
+            game.set_current_panorama(panorama);
+        }
+    }
+}
+
+/// Find the Street View panorama to show for the location, logging if we can't
+fn resolve_pano_id(resolver: &PanoramaResolver, location: &Location) -> Option<Panorama> {
+    match resolver.resolve(location) {
+        Ok(panorama) => Some(panorama),
+        Err(e) => {
+            log::error!("Could not find a panorama near {:?}: {:?}", location, e);
+            None
+        }
+    }
 }
 
 #[derive(Serialize)]
 struct ActualAndGuess {
     actual: Location,
-    guess: Location,
+    guess: Option<Location>,
+    right_country: Option<bool>,
 }
 
 #[derive(Serialize)]
-struct GameOverContext {
-    api_key: String,
+struct FinalResults {
     results: Vec<ActualAndGuess>,
     score: usize,
+
+    /// How many of the locations the player got the country right for, for places whose
+    /// datasets say what country the locations are in
+    right_countries: Option<usize>,
+
+    /// Link for somebody else to play the same locations
+    challenge: Option<String>,
+
+    /// Link to the leaderboard, for daily challenges
+    leaderboard: Option<String>,
+}
+
+fn right_countries(results: &[GuessResult]) -> Option<usize> {
+    if results.iter().all(|result| result.right_country.is_none()) {
+        return None;
+    }
+    Some(
+        results
+            .iter()
+            .filter(|result| result.right_country == Some(true))
+            .count(),
+    )
+}
+
+fn final_results(settings: &GameSettings, player: &Player) -> FinalResults {
+    FinalResults {
+        results: player
+            .results
+            .iter()
+            .map(|result| ActualAndGuess {
+                guess: result.guess.clone(),
+                actual: result.actual.clone(),
+                right_country: result.right_country,
+            })
+            .collect(),
+        score: player.points,
+        right_countries: right_countries(&player.results),
+        challenge: Challenge::new(settings).map(|challenge| challenge.link()),
+        leaderboard: settings
+            .daily
+            .map(|day| format!("daily/{}/{}", settings.dataset, day)),
+    }
+}
+
+#[derive(Serialize)]
+struct GameOverContext {
+    api_key: String,
+    #[serde(flatten)]
+    results: FinalResults,
+}
+
+#[derive(Serialize)]
+struct LobbyPlayer<'a> {
+    name: &'a str,
+    is_host: bool,
+}
+
+#[derive(Serialize)]
+struct LobbyContext<'a> {
+    api_key: String,
+    join_code: &'a str,
+    players: Vec<LobbyPlayer<'a>>,
+    is_host: bool,
+    settings: &'a GameSettings,
+    /// Rounded, since Tera can only round to a float
+    scoring_extent: Option<u32>,
 }
 
 #[derive(Deserialize)]
@@ -163,158 +879,514 @@ struct GoogleAuthentication {
     api_key: String,
 }
 
-fn render_playgame(auth: &GoogleAuthentication, game: &mut Game, playerid: usize) -> Template {
-    let player = game.get_player(playerid).unwrap();
-    if player.state == PlayerState::Guessing {
+fn render_lobby(auth: &GoogleAuthentication, game: &Game, playerid: usize) -> Template {
+    let mut player_ids = game.get_player_ids();
+    player_ids.sort_unstable();
+    let players = player_ids
+        .iter()
+        .filter_map(|pid| {
+            game.get_player(*pid).map(|player| LobbyPlayer {
+                name: &player.name,
+                is_host: game.is_host(*pid),
+            })
+        })
+        .collect();
+    let context = LobbyContext {
+        api_key: auth.api_key.clone(),
+        join_code: game.get_join_code(),
+        players,
+        is_host: game.is_host(playerid),
+        settings: game.get_settings(),
+        scoring_extent: game
+            .get_settings()
+            .scoring_extent
+            .map(|extent| extent.round() as u32),
+    };
+    Template::render("lobby", context)
+}
+
+fn render_playgame(
+    auth: &GoogleAuthentication,
+    game: &Game,
+    playerid: usize,
+) -> Result<Template, Error> {
+    let player = game
+        .get_player(playerid)
+        .ok_or(Error::UnknownPlayer(playerid))?;
+    if player.state == PlayerState::Joined {
+        Ok(render_lobby(auth, game, playerid))
+    } else if player.state == PlayerState::Guessing {
         let context = PlayGameContext {
             api_key: auth.api_key.clone(),
-            location: game.get_current_location(),
-            locations_remaining: game.get_locations_remaining(),
+            round: current_round(game),
         };
-        Template::render("playgame", &context)
+        Ok(Template::render("playgame", context))
+    } else if player.state == PlayerState::GuessResults {
+        let result = player
+            .results
+            .last()
+            .ok_or(Error::WrongState(player.state))?
+            .clone();
+        Ok(render_guess_result(auth, game, playerid, result))
     } else {
-        let mut results = vec![];
-        for i in 0..player.guesses.len() {
-            results.push(ActualAndGuess {
-                guess: player.guesses[i].clone(),
-                actual: game.get_location(i),
-            });
-        }
         let context = GameOverContext {
             api_key: auth.api_key.clone(),
-            results: results,
-            score: player.points,
+            results: final_results(game.get_settings(), player),
         };
-        Template::render("gameover", &context)
+        Ok(Template::render("gameover", context))
     }
 }
 
 #[post("/create-game", data = "<input>")]
 fn create_game(
     db: State<GuardedGames>,
-    google_auth: State<GoogleAuthentication>,
+    places: Places,
     mut cookies: Cookies,
     input: Form<CreateGame>,
-) -> Template {
+) -> Result<Redirect, status::Custom<Template>> {
+    let game = input
+        .settings(&places)
+        .map_err(CreateError::from)
+        .and_then(|settings| Games::new_game(settings, places.generator()));
     let mut db = db.inner().lock().unwrap();
-    let mut game = Game::new(5, &db.generator, &input.place);
+    let created = game.and_then(|game| db.create_game(game, nickname_or_default(&input.nickname)));
+    match created {
+        Ok(playerid) => {
+            let token = db.new_session(playerid);
+            set_session(&mut cookies, token);
+            Ok(Redirect::to("play-round"))
+        }
+        Err(e) => {
+            let status = match e {
+                CreateError::Settings(_) | CreateError::Locations(_) => Status::BadRequest,
+                CreateError::TooManyGames => Status::ServiceUnavailable,
+            };
+            Err(status::Custom(
+                status,
+                render_index(&places, None, Some(&e.message())),
+            ))
+        }
+    }
+}
 
-    let playerid = db.new_playerid();
-    game.add_player(playerid, "Player").unwrap();
+#[derive(Serialize)]
+struct ChallengeContext<'a> {
+    challenge: &'a Challenge,
+    place_name: &'a str,
+    settings: &'a GameSettings,
 
-    db.add_game(game);
+    /// Formatted here, since Tera would write 100km as 100.0
+    min_spacing: String,
+}
 
-    let game = db.get_game(playerid).unwrap();
-    let mut game = game.0.lock().unwrap();
+#[get("/challenge?<challenge..>")]
+fn challenge(
+    places: Places,
+    challenge: Form<Challenge>,
+) -> Result<Template, status::Custom<Template>> {
+    let settings = challenge.create_game().settings(&places).map_err(|e| {
+        status::Custom(
+            Status::BadRequest,
+            render_index(&places, None, Some(&e.to_string())),
+        )
+    })?;
+    let place_name = places
+        .iter()
+        .find(|place| place.key == settings.dataset)
+        .map_or("", |place| place.human_name.as_str());
+    let context = ChallengeContext {
+        challenge: &challenge,
+        place_name,
+        settings: &settings,
+        min_spacing: settings.min_spacing_km.to_string(),
+    };
+    Ok(Template::render("challenge", context))
+}
 
-    // TODO: Only for single-player games
-    game.start();
+#[derive(FromForm, Deserialize)]
+struct DailyPlayer {
+    nickname: String,
+}
 
-    let cookie = Cookie::build("playerid", format!("{}", playerid)).finish();
-    cookies.add(cookie);
+fn daily_place<'a>(places: &'a [PlaceSpec], place: &str) -> Option<&'a PlaceSpec> {
+    places.iter().find(|spec| spec.key == place && spec.daily)
+}
 
-    render_playgame(&google_auth, &mut game, playerid)
+/// Name of the (private) cookie remembering the last day the player played the place
+fn daily_cookie(place: &str) -> String {
+    format!("daily-{}", place)
 }
 
-#[get("/play-round")]
-fn play_round(
-    google_auth: State<GoogleAuthentication>,
-    game: GuardedGameAndPid,
-    cookies: Cookies,
-) -> Template {
-    let playerid = game.1;
-    let mut game = game.0.lock().unwrap();
-    render_playgame(&google_auth, &mut game, playerid)
+/// Whether the player has already started today's challenge for the place, going by their
+/// cookies and the sessions the leaderboard remembers. Anybody who clears their cookies gets
+/// another go.
+fn played_today(db: &Games, cookies: &mut Cookies, place: &str) -> bool {
+    let today = Day::today();
+    let cookie = cookies
+        .get_private(&daily_cookie(place))
+        .map_or(false, |cookie| cookie.value() == today.to_string());
+    let session = cookies
+        .get_private(SESSION_COOKIE)
+        .map_or(false, |session| {
+            db.leaderboard.started(place, today, session.value())
+        });
+    cookie || session
 }
 
-#[derive(FromForm)]
-struct LocationGuess {
-    lat: f64,
-    lon: f64,
+/// Start a game of today's challenge for the place, and give the player a session for it
+fn start_daily(
+    db: &GuardedGames,
+    places: &Places,
+    cookies: &mut Cookies,
+    place: &str,
+    nickname: &str,
+) -> Result<PlayerId, DailyError> {
+    let spec = daily_place(places, place).ok_or(DailyError::NoChallenge)?;
+    if played_today(&db.lock().unwrap(), cookies, place) {
+        return Err(DailyError::AlreadyPlayed);
+    }
+    let today = Day::today();
+    let mut settings = GameSettings::new(place);
+    settings.seed = Some(today.seed(place));
+    settings.daily = Some(today);
+    settings.scoring_extent = spec.scoring_extent_km;
+    settings.min_spacing_km = spec.min_spacing_km.unwrap_or(0.0);
+
+    let game = Games::new_game(settings, places.generator()).map_err(DailyError::Create)?;
+    let mut db = db.lock().unwrap();
+    // Check again, in case the same player started it while the locations were being picked
+    if played_today(&db, cookies, place) {
+        return Err(DailyError::AlreadyPlayed);
+    }
+    let playerid = db.create_game(game, nickname).map_err(DailyError::Create)?;
+    // Nobody else is going to join, so there's no point waiting around in the lobby
+    db.get_game(playerid)
+        .unwrap()
+        .0
+        .lock()
+        .unwrap()
+        .start()
+        .unwrap();
+    let previous = cookies
+        .get_private(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+    let token = db.new_session(playerid);
+    let sessions: Vec<_> = previous
+        .iter()
+        .chain(Some(&token))
+        .map(|s| s.as_str())
+        .collect();
+    db.leaderboard.start(place, today, &sessions);
+    let serialized = serde_json::to_string(&db.leaderboard).unwrap();
+    db.storage.save_leaderboard(serialized);
+    set_session(cookies, token);
+    cookies.add_private(Cookie::new(daily_cookie(place), today.to_string()));
+    Ok(playerid)
 }
 
 #[derive(Serialize)]
-struct GuessResultContext {
-    api_key: String,
-    result: GuessResult,
-    locations_remaining: usize,
+struct DailyContext<'a> {
+    place: &'a PlaceSpec,
+    day: Day,
+    can_play: bool,
+    scores: &'a [DailyScore],
+
+    /// Every day with scores, plus today
+    days: Vec<Day>,
+    error: Option<&'a str>,
 }
 
-#[post("/guess", data = "<guess>")]
-fn guess(
-    google_auth: State<GoogleAuthentication>,
-    game: GuardedGameAndPid,
-    cookies: Cookies,
-    guess: Form<LocationGuess>,
+fn render_daily(
+    db: &Games,
+    place: &PlaceSpec,
+    day: Day,
+    played_today: bool,
+    error: Option<&str>,
 ) -> Template {
-    let playerid = game.1;
-    let mut game = game.0.lock().unwrap();
-    log::info!("Received guess attempt from playerid={}", playerid);
-    let guess = Location {
-        latitude: guess.lat,
-        longitude: guess.lon,
+    let today = Day::today();
+    let mut days = db.leaderboard.days(&place.key);
+    if !days.contains(&today) {
+        days.insert(0, today);
+    }
+    let context = DailyContext {
+        place,
+        day,
+        can_play: day == today && !played_today,
+        scores: db.leaderboard.scores(&place.key, day),
+        days,
+        error,
     };
-    let guess_result = game.guess(playerid, &guess).unwrap();
-    log::info!(
-        "Guess result for playerid {} was {:?}",
-        playerid,
+    Template::render("daily", context)
+}
+
+#[get("/daily/<place>")]
+fn daily_today(places: Places, uri: &Origin, place: String) -> Option<Redirect> {
+    daily_place(&places, &place)?;
+    Some(Redirect::to(format!(
+        "{}/{}",
+        uri.path().trim_end_matches('/'),
+        Day::today()
+    )))
+}
+
+#[get("/daily/<place>/<day>")]
+fn daily(
+    db: State<GuardedGames>,
+    places: Places,
+    mut cookies: Cookies,
+    place: String,
+    day: String,
+) -> Option<Template> {
+    let place = daily_place(&places, &place)?;
+    let day = day.parse().ok()?;
+    let db = db.lock().unwrap();
+    let played = played_today(&db, &mut cookies, &place.key);
+    Some(render_daily(&db, place, day, played, None))
+}
+
+/// Where the rest of the pages are mounted, given the path to a daily challenge
+fn daily_root<'a>(uri: &'a Origin) -> &'a str {
+    let path = uri.path();
+    path.rfind("/daily/").map_or("", |i| &path[..i])
+}
+
+#[post("/daily/<place>", data = "<input>")]
+fn play_daily(
+    db: State<GuardedGames>,
+    places: Places,
+    uri: &Origin,
+    mut cookies: Cookies,
+    place: String,
+    input: Form<DailyPlayer>,
+) -> Result<Redirect, status::Custom<Template>> {
+    let nickname = nickname_or_default(&input.nickname);
+    match start_daily(&db, &places, &mut cookies, &place, nickname) {
+        Ok(_) => Ok(Redirect::to(format!("{}/play-round", daily_root(uri)))),
+        Err(DailyError::NoChallenge) => Err(status::Custom(
+            Status::NotFound,
+            render_index(&places, None, Some(&DailyError::NoChallenge.message())),
+        )),
+        Err(e) => {
+            let status = match e {
+                DailyError::Create(CreateError::TooManyGames) => Status::ServiceUnavailable,
+                // The place's own spacing is too much for it, which is the config's fault
+                DailyError::Create(CreateError::Locations(_)) => Status::InternalServerError,
+                _ => Status::Conflict,
+            };
+            let place = daily_place(&places, &place).unwrap();
+            let page = render_daily(
+                &db.lock().unwrap(),
+                place,
+                Day::today(),
+                true,
+                Some(&e.message()),
+            );
+            Err(status::Custom(status, page))
+        }
+    }
+}
+
+#[post("/join-game", data = "<input>")]
+fn join_game(
+    db: State<GuardedGames>,
+    places: Places,
+    mut cookies: Cookies,
+    input: Form<JoinGame>,
+) -> Result<Redirect, Template> {
+    let mut db = db.inner().lock().unwrap();
+    match db.join_game(&input.code, nickname_or_default(&input.nickname)) {
+        Ok(playerid) => {
+            let token = db.new_session(playerid);
+            set_session(&mut cookies, token);
+            Ok(Redirect::to("play-round"))
+        }
+        Err(e) => Err(render_index(
+            &places,
+            Some(input.code.clone()),
+            Some(e.message()),
+        )),
+    }
+}
+
+#[post("/start-game")]
+fn start_game(game: GuardedGameAndPid) -> Result<Redirect, ErrorPage> {
+    let playerid = game.1;
+    let mut game = game.0.lock().unwrap();
+    if !game.is_host(playerid) {
+        return Err(ErrorPage {
+            status: Status::Forbidden,
+            message: "Only the host can start the game.".to_string(),
+        });
+    }
+    game.start()?;
+    Ok(Redirect::to("play-round"))
+}
+
+#[get("/play-round")]
+fn play_round(
+    google_auth: State<GoogleAuthentication>,
+    resolver: State<PanoramaResolver>,
+    game: GuardedGameAndPid,
+) -> Result<Template, ErrorPage> {
+    let playerid = game.1;
+    resolve_current_panorama(&resolver, &game.0, playerid);
+    let game = game.0.lock().unwrap();
+    Ok(render_playgame(&google_auth, &game, playerid)?)
+}
+
+#[derive(FromForm, Deserialize)]
+struct LocationGuess {
+    lat: f64,
+    lon: f64,
+
+    /// The round being guessed, so that a guess sent twice doesn't count for the next one
+    round: Option<usize>,
+}
+
+impl LocationGuess {
+    /// Whether the guess is for a round that's over
+    fn too_late(&self, game: &Game) -> bool {
+        self.round.map_or(false, |round| round != game.get_round())
+    }
+}
+
+#[derive(Serialize)]
+struct GuessResultContext {
+    api_key: String,
+    playerid: PlayerId,
+    is_host: bool,
+
+    /// Whether the player is waiting for the game to move on
+    ready: bool,
+    advance: AdvancePolicy,
+    result: GuessResult,
+    locations_remaining: usize,
+    seconds_remaining: Option<u64>,
+}
+
+fn render_guess_result(
+    auth: &GoogleAuthentication,
+    game: &Game,
+    playerid: PlayerId,
+    result: GuessResult,
+) -> Template {
+    let context = GuessResultContext {
+        api_key: auth.api_key.clone(),
+        playerid,
+        is_host: game.is_host(playerid),
+        ready: game
+            .get_player(playerid)
+            .map_or(false, |player| player.ready),
+        advance: game.get_settings().advance,
+        result,
+        locations_remaining: game.get_locations_remaining(),
+        seconds_remaining: seconds_remaining(game),
+    };
+    Template::render("guess_result", context)
+}
+
+#[post("/guess", data = "<guess>")]
+fn guess(
+    google_auth: State<GoogleAuthentication>,
+    places: Places,
+    game: GuardedGameAndPid,
+    guess: Form<LocationGuess>,
+) -> Result<Template, ErrorPage> {
+    let playerid = game.1;
+    let mut game = game.0.lock().unwrap();
+    log::info!("Received guess attempt from playerid={}", playerid);
+    if guess.too_late(&game) {
+        return Err(Error::AlreadyGuessed.into());
+    }
+    let guess = Location {
+        latitude: guess.lat,
+        longitude: guess.lon,
+    };
+    let guess_result = game.guess(playerid, &guess, places.generator())?;
+    log::info!(
+        "Guess result for playerid {} was {:?}",
+        playerid,
         guess_result
     );
 
     // Show guess results
-    let data = GuessResultContext {
-        api_key: google_auth.api_key.clone(),
-        result: guess_result,
-        locations_remaining: game.get_locations_remaining(),
-    };
-    Template::render("guess_result", &data)
+    Ok(render_guess_result(
+        &google_auth,
+        &game,
+        playerid,
+        guess_result,
+    ))
 }
 
 #[get("/advance-guess")]
 fn advance_guess(
     google_auth: State<GoogleAuthentication>,
+    resolver: State<PanoramaResolver>,
     game: GuardedGameAndPid,
-    cookies: Cookies,
-) -> Template {
+) -> Result<Template, ErrorPage> {
     let playerid = game.1;
-    let mut game = game.0.lock().unwrap();
 
-    game.advance_guess().unwrap();
-    render_playgame(&google_auth, &mut game, playerid)
+    // Players who are done wait on the results page until the game moves on
+    game.0.lock().unwrap().ready(playerid)?;
+    resolve_current_panorama(&resolver, &game.0, playerid);
+    let game = game.0.lock().unwrap();
+    Ok(render_playgame(&google_auth, &game, playerid)?)
 }
 
 #[get("/game-poller")]
-fn game_poller(db: State<GuardedGames>, cookies: Cookies) -> String {
-    let playerid = cookies.get("playerid").unwrap().value();
-    let playerid = playerid.parse::<usize>().unwrap();
-    let mut db = db.inner().lock().unwrap();
-    //serde_json::to_string(db.get_game(playerid)).unwrap()
-    "Test".to_string()
+fn game_poller(game: GuardedGameAndPid) -> Json<GameView> {
+    let game = game.0.lock().unwrap();
+    Json(game.view())
+}
+
+/// Everything that happens in the player's game, as it happens.
+///
+/// Fails with 503 when too many streams are open, and the page falls back to polling
+#[get("/game-events")]
+fn game_events(slots: State<StreamSlots>, game: GuardedGameAndPid) -> Result<EventStream, Status> {
+    let slot = slots.take().ok_or(Status::ServiceUnavailable)?;
+    Ok(EventStream::new(game.0.lock().unwrap().subscribe(), slot))
 }
 
 #[get("/random/<dataset>")]
 fn random(
-    db: State<GuardedGames>,
+    places: Places,
     google_auth: State<GoogleAuthentication>,
+    resolver: State<PanoramaResolver>,
     dataset: String,
-) -> Template {
-    let db = db.inner().lock().unwrap();
+) -> Option<Template> {
+    let location = places.generator().sample_from_dataset(&dataset).ok()?;
     let context = PlayGameContext {
         api_key: google_auth.api_key.clone(),
-        location: db.generator.sample_from_dataset(&dataset),
-        locations_remaining: 0,
+        round: RoundContext {
+            pano_id: resolve_pano_id(&resolver, &location).map(|p| p.pano_id),
+            locations_remaining: 0,
+            seconds_remaining: None,
+            round: 1,
+            num_rounds: 1,
+            movement: MovementRule::Moving,
+        },
     };
-    Template::render("playgame", &context)
+    Some(Template::render("playgame", context))
 }
 
+/// Sets up the server, which comes configured (with its port, secret key and so on)
 fn rocket(
+    rocket: rocket::Rocket,
     google_auth: GoogleAuthentication,
     root: &'static str,
-    places: Vec<PlaceSpec>,
-    location_gen: LocationGenerator,
+    catalog: GuardedCatalog,
+    resolver: PanoramaResolver,
+    storage: GameStorage,
+    limits: Limits,
 ) -> rocket::Rocket {
-    let db = Arc::new(Mutex::new(Games::new(location_gen)));
-    rocket::ignite()
+    let max_event_streams = limits.max_event_streams;
+    let db = Arc::new(Mutex::new(Games::new(storage, limits)));
+    spawn_ticker(Arc::downgrade(&db));
+    let api_root = format!("{}/api/v1", root.trim_end_matches('/'));
+    let workers = rocket.config().workers as usize;
+    rocket
+        .mount(&api_root, api::routes())
         .mount(
             root,
             routes![
@@ -322,8 +1394,15 @@ fn rocket(
                 index,
                 hello,
                 create_game,
+                challenge,
+                daily_today,
+                daily,
+                play_daily,
+                join_game,
+                start_game,
                 guess,
                 game_poller,
+                game_events,
                 play_round,
                 advance_guess,
                 random,
@@ -333,64 +1412,168 @@ fn rocket(
             engines
                 .tera
                 .add_raw_templates(vec![
-                    ("gameover", include_str!("../templates/index.html.tera")),
+                    (
+                        "challenge",
+                        include_str!("../templates/challenge.html.tera"),
+                    ),
+                    ("gameover", include_str!("../templates/gameover.html.tera")),
                     (
                         "guess_result",
                         include_str!("../templates/guess_result.html.tera"),
                     ),
+                    ("error", include_str!("../templates/error.html.tera")),
                     ("index", include_str!("../templates/index.html.tera")),
+                    ("lobby", include_str!("../templates/lobby.html.tera")),
                     ("main", include_str!("../templates/main.html.tera")),
-                    ("playgame", "playgame.html.tera"),
+                    ("playgame", include_str!("../templates/playgame.html.tera")),
                 ])
                 .unwrap();
         }))
-        .manage(places)
+        .register(catchers![unauthorized])
+        .manage(catalog)
         .manage(db)
         .manage(google_auth)
+        .manage(resolver)
+        .manage(StreamSlots::new(
+            max_event_streams.unwrap_or((workers / 2).max(1)),
+        ))
 }
 
 #[derive(Deserialize)]
 struct Config {
     authentication: GoogleAuthentication,
     places: Vec<PlaceSpec>,
+    storage: Option<StorageConfig>,
+    #[serde(default)]
+    limits: Limits,
+
+    /// Read through every dataset at startup to check it against its checksum, rather
+    /// than trusting the header
+    #[serde(default)]
+    verify_datasets: bool,
 }
 
-fn main() {
-    let config: Config =
-        serde_yaml::from_str(&std::fs::read_to_string("config.yaml").unwrap()).unwrap();
+const CONFIG_PATH: &str = "config.yaml";
 
-    let places: Vec<_> = config
-        .places
-        .iter()
-        .map(|p| (p.key.as_str(), p.filename.as_str()))
-        .collect();
+fn read_config(path: &str) -> Result<Config, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    serde_yaml::from_str(&contents).map_err(|e| format!("Could not understand {}: {}", path, e))
+}
+
+fn main() {
+    let config = read_config(CONFIG_PATH).unwrap();
 
-    let location_gen = LocationGenerator::from_datafile(places.as_slice());
+    // Carry on with whatever loaded, rather than letting one bad file take everything down
+    let catalog = Catalog::load(config.places, config.verify_datasets);
+    assert!(
+        !catalog.places().is_empty(),
+        "None of the places in config.yaml could be loaded"
+    );
+    let catalog = catalog.guarded();
+    spawn_reloader(catalog.clone(), CONFIG_PATH);
 
     let google_auth: GoogleAuthentication = config.authentication;
+    let resolver = PanoramaResolver::google(&google_auth.api_key);
+    let storage = config
+        .storage
+        .unwrap_or(StorageConfig::Memory)
+        .build()
+        .expect("Could not set up game storage");
 
-    rocket(google_auth, "/placeguessr", config.places, location_gen).launch();
+    rocket(
+        rocket::ignite(),
+        google_auth,
+        "/placeguessr",
+        catalog,
+        resolver,
+        storage,
+        config.limits,
+    )
+    .launch();
 }
 
 #[cfg(test)]
 mod test {
     use super::rocket;
+    use crate::datafile::test::TempDir;
+    use crate::reap_games;
+    use crate::tick_games;
+    use crate::Catalog;
+    use crate::DatasetSource;
+    use crate::Day;
+    use crate::GameSettings;
+    use crate::GameStorage;
+    use crate::Games;
     use crate::GoogleAuthentication;
+    use crate::GuardedCatalog;
+    use crate::GuardedGames;
+    use crate::Limits;
     use crate::LocationGenerator;
+    use crate::PanoramaResolver;
+    use crate::PlaceSpec;
     use rocket::http::ContentType;
+    use rocket::http::Cookie;
     use rocket::http::Status;
     use rocket::local::Client;
 
-    fn mkrocket() -> rocket::Rocket {
-        let places = vec![PlaceSpec {
-            key: "us",
-            filename: "roads-us.dat",
-            human_name: "US",
-        }];
+    pub fn mkrocket() -> rocket::Rocket {
+        mkrocket_with_storage(GameStorage::memory())
+    }
+
+    fn mkrocket_with_storage(storage: GameStorage) -> rocket::Rocket {
+        mkrocket_with(storage, Limits::default())
+    }
+
+    fn mkrocket_with(storage: GameStorage, limits: Limits) -> rocket::Rocket {
+        let catalog = Catalog::new(test_places(), LocationGenerator::mock());
+        mkrocket_with_catalog(catalog.guarded(), storage, limits)
+    }
+
+    fn test_places() -> Vec<PlaceSpec> {
+        vec![
+            PlaceSpec {
+                key: "us".to_string(),
+                source: DatasetSource::file("roads-us.dat"),
+                human_name: "US".to_string(),
+                scoring_extent_km: Some(4_500.0),
+                daily: false,
+                min_spacing_km: Some(100.0),
+            },
+            PlaceSpec {
+                key: "world".to_string(),
+                source: DatasetSource::file("roads.dat"),
+                human_name: "World".to_string(),
+                scoring_extent_km: None,
+                daily: true,
+                min_spacing_km: None,
+            },
+        ]
+    }
+
+    fn mkrocket_with_catalog(
+        catalog: GuardedCatalog,
+        storage: GameStorage,
+        limits: Limits,
+    ) -> rocket::Rocket {
         let mock_auth = GoogleAuthentication {
             api_key: "1234".to_string(),
         };
-        rocket(mock_auth, "/", places, LocationGenerator::mock())
+        // Sessions can only be decrypted by a server with the same key, which every test
+        // server has, so that they can be restarted
+        let config = rocket::Config::build(rocket::config::Environment::Development)
+            .secret_key("hPRYyVRiMyxpw5sBB1XeCMN1kFsDCqKvBi2QJxBVHQk=")
+            .finalize()
+            .unwrap();
+        rocket(
+            rocket::custom(config),
+            mock_auth,
+            "/",
+            catalog,
+            PanoramaResolver::mock(),
+            storage,
+            limits,
+        )
     }
 
     #[test]
@@ -406,8 +1589,11 @@ mod test {
         let response = client
             .post("/create-game")
             .header(ContentType::Form)
-            .body("place=world")
+            .body("place=world&nickname=Host")
             .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+
+        let response = client.get("/play-round").dispatch();
         assert_eq!(response.status(), Status::Ok);
     }
 
@@ -417,20 +1603,756 @@ mod test {
         let response = client
             .post("/create-game")
             .header(ContentType::Form)
-            .body("place=world")
+            .body("place=world&nickname=")
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+
+        let response = client.post("/start-game").dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+
+        // Guess 5 times, moving on as soon as each guess is in
+        for round in 1..=5 {
+            let guess = || {
+                client
+                    .post("/guess")
+                    .header(ContentType::Form)
+                    .body(format!("lat=30&lon=-90&round={}", round))
+                    .dispatch()
+            };
+            assert_eq!(guess().status(), Status::Ok);
+            // A second click doesn't guess the next location
+            assert_eq!(guess().status(), Status::Conflict);
+
+            let response = client.get("/play-round").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        }
+    }
+
+    #[test]
+    fn challenge_link() {
+        let client = Client::new(mkrocket()).unwrap();
+        client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=us&nickname=Host&rounds=1&round_time=30&seed=1234")
+            .dispatch();
+        client.post("/start-game").dispatch();
+        client
+            .post("/guess")
+            .header(ContentType::Form)
+            .body("lat=30&lon=-90")
+            .dispatch();
+        let mut response = client.get("/play-round").dispatch();
+        let link =
+            "challenge?place=us&rounds=1&round_time=30&scoring=standard&movement=moving&min_spacing=100&seed=1234";
+        assert!(response.body_string().unwrap().contains(link));
+
+        let mut response = client.get(format!("/{}", link)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body_string().unwrap();
+        assert!(body.contains("guess 1 locations in US"));
+        assert!(body.contains(r#"name="seed" value="1234""#));
+        assert!(body.contains(r#"name="min_spacing" value="100""#));
+
+        // Links from before spacing still work, without any
+        let mut response = client
+            .get("/challenge?place=us&rounds=1&round_time=&scoring=standard&movement=moving&seed=1")
+            .dispatch();
+        assert!(response
+            .body_string()
+            .unwrap()
+            .contains(r#"name="min_spacing" value="0""#));
+
+        let response = client
+            .get("/challenge?place=mars&rounds=1&round_time=&scoring=standard&movement=moving&seed=1")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn reload_places() {
+        let catalog = Catalog::new(test_places(), LocationGenerator::mock()).guarded();
+        let client = Client::new(mkrocket_with_catalog(
+            catalog.clone(),
+            GameStorage::memory(),
+            Limits::default(),
+        ))
+        .unwrap();
+        client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=us&nickname=Host&rounds=2")
+            .dispatch();
+        client.post("/start-game").dispatch();
+
+        let mut places = test_places();
+        places.retain(|p| p.key != "us");
+        places[0].key = "japan".to_string();
+        places[0].human_name = "Japan".to_string();
+        *catalog.write().unwrap() =
+            std::sync::Arc::new(Catalog::new(places, LocationGenerator::mock()));
+
+        let mut response = client.get("/").dispatch();
+        let body = response.body_string().unwrap();
+        assert!(body.contains("Japan"));
+        assert!(!body.contains(r#"value="us""#));
+
+        // The game that was already going carries on with its own locations
+        let response = client
+            .post("/guess")
+            .header(ContentType::Form)
+            .body("lat=30&lon=-90")
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
 
-        // Guess 5 times
-        for _ in 0..5 {
+        let response = client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=us&nickname=Host")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=japan&nickname=Host")
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+    }
+
+    #[test]
+    fn spacing_too_big() {
+        let dir = TempDir::new("spacing");
+        let path = dir.file("pair.dat");
+        // At the corners of the header's bounding box, about 4900km apart
+        std::fs::write(
+            &path,
+            crate::datafile::test::write(&[(-10.0, -20.0), (10.0, 20.0)], "x"),
+        )
+        .unwrap();
+        let mut places = test_places();
+        places[0].key = "pair".to_string();
+        places[0].source = DatasetSource::file(&path);
+        places.truncate(1);
+        let catalog = Catalog::load(places, true).guarded();
+        let client = Client::new(mkrocket_with_catalog(
+            catalog,
+            GameStorage::memory(),
+            Limits::default(),
+        ))
+        .unwrap();
+        let create = |spacing| {
+            client
+                .post("/create-game")
+                .header(ContentType::Form)
+                .body(format!(
+                    "place=pair&nickname=Host&rounds=2&min_spacing={}",
+                    spacing
+                ))
+                .dispatch()
+        };
+        let mut response = create(3000);
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response
+            .body_string()
+            .unwrap()
+            .contains("can't be spaced more than 2472km apart"));
+        assert_eq!(create(2000).status(), Status::SeeOther);
+    }
+
+    #[test]
+    fn right_country() {
+        let dir = TempDir::new("japan");
+        let path = dir.file("japan.dat");
+        std::fs::write(
+            &path,
+            crate::datafile::test::write_regions(&[("JP-13", 1)], &[(35.7, 139.7)], "x"),
+        )
+        .unwrap();
+        let mut places = test_places();
+        places[0].key = "japan".to_string();
+        places[0].source = DatasetSource::file(&path);
+        places.truncate(1);
+        let catalog = Catalog::load(places, true).guarded();
+        let client = Client::new(mkrocket_with_catalog(
+            catalog,
+            GameStorage::memory(),
+            Limits::default(),
+        ))
+        .unwrap();
+        client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=japan&nickname=Host&rounds=1&scoring=country-bonus")
+            .dispatch();
+        client.post("/start-game").dispatch();
+        let mut response = client
+            .post("/guess")
+            .header(ContentType::Form)
+            .body("lat=35.75&lon=139.65")
+            .dispatch();
+        let body = response.body_string().unwrap();
+        assert!(body.contains("You guessed the right country!"));
+        assert!(body.contains("plus a 2000 point bonus for the country"));
+
+        let mut response = client.get("/play-round").dispatch();
+        assert!(response
+            .body_string()
+            .unwrap()
+            .contains("You got the country right 1 out of 1 times."));
+    }
+
+    #[test]
+    fn daily_challenge() {
+        let client = Client::new(mkrocket()).unwrap();
+        let today = Day::today().to_string();
+        let response = client.get("/daily/world").dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some(format!("/daily/world/{}", today).as_str())
+        );
+        let response = client.get("/daily/us").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.get("/daily/world/someday").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let mut response = client.get(format!("/daily/world/{}", today)).dispatch();
+        assert!(response
+            .body_string()
+            .unwrap()
+            .contains("Play today's challenge"));
+
+        let response = client
+            .post("/daily/world")
+            .header(ContentType::Form)
+            .body("nickname=Daily")
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        client
+            .post("/guess")
+            .header(ContentType::Form)
+            .body("lat=30&lon=-90")
+            .dispatch();
+        for _ in 0..4 {
+            client.get("/play-round").dispatch();
+            client
+                .post("/guess")
+                .header(ContentType::Form)
+                .body("lat=30&lon=-90")
+                .dispatch();
+        }
+        let mut response = client.get("/play-round").dispatch();
+        let body = response.body_string().unwrap();
+        assert!(body.contains(&format!("daily/world/{}", today)));
+        assert!(!body.contains("challenge?"));
+
+        // Only one go per day
+        let mut response = client
+            .post("/daily/world")
+            .header(ContentType::Form)
+            .body("nickname=Again")
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        assert!(response.body_string().unwrap().contains("already played"));
+
+        // Scores make it onto the leaderboard once the game is over
+        tick_games(client.rocket().state::<GuardedGames>().unwrap());
+        let mut response = client.get(format!("/daily/world/{}", today)).dispatch();
+        let body = response.body_string().unwrap();
+        assert!(body.contains("Daily: "));
+        assert!(!body.contains("Play today's challenge"));
+
+        // Everybody gets the same locations
+        let mut db = client
+            .rocket()
+            .state::<GuardedGames>()
+            .unwrap()
+            .lock()
+            .unwrap();
+        let game = db.get_game(1).unwrap();
+        let game = game.0.lock().unwrap();
+        assert_eq!(game.get_settings().seed, Some(Day::today().seed("world")));
+        assert_eq!(game.get_settings().daily, Some(Day::today()));
+    }
+
+    #[test]
+    fn daily_challenge_lost_cookie() {
+        let client = Client::untracked(mkrocket()).unwrap();
+        let play = |cookies: Vec<Cookie<'static>>| {
+            client
+                .post("/daily/world")
+                .header(ContentType::Form)
+                .cookies(cookies)
+                .body("nickname=Daily")
+                .dispatch()
+        };
+        let response = play(vec![]);
+        assert_eq!(response.status(), Status::SeeOther);
+        let session: Vec<_> = response
+            .cookies()
+            .into_iter()
+            .filter(|cookie| cookie.name() == "session")
+            .map(|cookie| cookie.into_owned())
+            .collect();
+        assert_eq!(session.len(), 1);
+
+        // Without the cookie saying they've played, the server still knows them by their
+        // session
+        let mut response = play(session);
+        assert_eq!(response.status(), Status::Conflict);
+        assert!(response.body_string().unwrap().contains("already played"));
+        assert_eq!(play(vec![]).status(), Status::SeeOther);
+    }
+
+    #[test]
+    fn double_clicks() {
+        let client = Client::new(mkrocket()).unwrap();
+        client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Host&rounds=1&advance=host")
+            .dispatch();
+        client.post("/start-game").dispatch();
+
+        let guess = || {
+            client
+                .post("/guess")
+                .header(ContentType::Form)
+                .body("lat=30&lon=-90")
+                .dispatch()
+        };
+        assert_eq!(guess().status(), Status::Ok);
+        let mut response = guess();
+        assert_eq!(response.status(), Status::Conflict);
+        assert!(response
+            .body_string()
+            .unwrap()
+            .contains("You've already guessed this location."));
+
+        let response = client.get("/advance-guess").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let mut response = client.get("/advance-guess").dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        assert!(response
+            .body_string()
+            .unwrap()
+            .contains("The game is over."));
+
+        // The game is still there to look at
+        let response = client.get("/play-round").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn playgame_hides_location() {
+        let client = Client::new(mkrocket()).unwrap();
+        client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Host")
+            .dispatch();
+        client.post("/start-game").dispatch();
+
+        let mut response = client.get("/play-round").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body_string().unwrap();
+        assert!(body.contains("buildPanorama(\"mock-pano\", \"moving\")"));
+        assert!(!body.contains("latitude"));
+        assert!(!body.contains("longitude"));
+    }
+
+    #[test]
+    fn create_game_with_settings() {
+        let client = Client::new(mkrocket()).unwrap();
+        let response = client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=us&nickname=Host&rounds=3&round_time=60&results_time=&scoring=exponential&movement=no-move")
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+
+        let mut response = client.get("/play-round").dispatch();
+        let body = response.body_string().unwrap();
+        assert!(body.contains("3 locations"));
+        assert!(body.contains("60 seconds per location"));
+        assert!(body.contains("scaled to a map 4500km across"));
+
+        client.post("/start-game").dispatch();
+        let mut response = client.get("/play-round").dispatch();
+        assert!(response.body_string().unwrap().contains("Location 1 of 3"));
+    }
+
+    #[test]
+    fn create_game_with_bad_settings() {
+        let client = Client::new(mkrocket()).unwrap();
+        for body in [
+            "place=mars&nickname=Host",
+            "place=us&nickname=Host&rounds=0",
+            "place=us&nickname=Host&round_time=100000",
+            "place=us&nickname=Host&scoring=lenient",
+            "place=us&nickname=Host&movement=teleport",
+        ]
+        .iter()
+        {
             let response = client
+                .post("/create-game")
+                .header(ContentType::Form)
+                .body(body)
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            assert!(response.cookies().is_empty());
+        }
+    }
+
+    #[test]
+    fn resume_after_restart() {
+        let directory = TempDir::new("restart");
+        let storage = || GameStorage::file(directory.path().to_str().unwrap()).unwrap();
+
+        let host = {
+            let client = Client::untracked(mkrocket_with_storage(storage())).unwrap();
+            let host = client
+                .post("/create-game")
+                .header(ContentType::Form)
+                .body("place=world&nickname=Host&rounds=3&advance=host")
+                .dispatch()
+                .cookies()
+                .into_iter()
+                .map(|cookie| cookie.into_owned())
+                .collect::<Vec<_>>();
+            client.post("/start-game").cookies(host.clone()).dispatch();
+            client
                 .post("/guess")
                 .header(ContentType::Form)
+                .cookies(host.clone())
                 .body("lat=30&lon=-90")
                 .dispatch();
-            assert_eq!(response.status(), Status::Ok);
+            tick_games(client.rocket().state::<GuardedGames>().unwrap());
+            host
+        };
+
+        // The server comes back up, and the player carries on where they left off
+        let client = Client::untracked(mkrocket_with_storage(storage())).unwrap();
+        let response = client.get("/advance-guess").cookies(host).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // New players don't get mixed up with the old ones
+        let response = client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Newcomer")
+            .dispatch();
+        let mut response = client
+            .get("/game-poller")
+            .cookies(response.cookies())
+            .dispatch();
+        let view: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(view["player_names"]["2"], "Newcomer");
+    }
+
+    #[test]
+    fn failed_joins_keep_ids() {
+        let mut db = Games::new(GameStorage::memory(), Limits::default());
+        let generator = LocationGenerator::mock();
+        let game = || {
+            Games::new_game(GameSettings::new("world"), &generator)
+                .ok()
+                .unwrap()
+        };
+        let host = db.create_game(game(), "Host").ok().unwrap();
+        let game_of = |db: &mut Games, playerid| db.get_game(playerid).unwrap().0;
+        let code = game_of(&mut db, host)
+            .lock()
+            .unwrap()
+            .get_join_code()
+            .to_string();
+        game_of(&mut db, host).lock().unwrap().start().unwrap();
+        assert!(db.join_game(&code, "Latecomer").is_err());
+        assert!(db.join_game("NOPE", "Lost").is_err());
+
+        // Nobody ends up with an id for a game they never got into
+        assert_eq!(db.create_game(game(), "Next").ok(), Some(host + 1));
+    }
 
-            let response = client.get("/advance-guess").dispatch();
+    #[test]
+    fn ticker_stops() {
+        let client = Client::new(mkrocket()).unwrap();
+        let db = std::sync::Arc::downgrade(client.rocket().state::<GuardedGames>().unwrap());
+        drop(client);
+        // Unless the ticker is in the middle of a tick, the games are gone straight away
+        for _ in 0..50 {
+            if db.upgrade().is_none() {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        panic!("The ticker kept the games around");
+    }
+
+    #[test]
+    fn forged_session() {
+        let client = Client::untracked(mkrocket()).unwrap();
+        let response = client.get("/play-round").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Host")
+            .dispatch();
+        let token = {
+            let db = client.rocket().state::<GuardedGames>().unwrap();
+            let db = db.lock().unwrap();
+            db.sessions.keys().next().unwrap().clone()
+        };
+        for cookie in [
+            Cookie::new("playerid", "1"),
+            Cookie::new("session", "1"),
+            // Even the real token is no good without the server's encryption
+            Cookie::new("session", token),
+        ] {
+            let mut response = client.get("/play-round").cookie(cookie).dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+            assert!(response
+                .body_string()
+                .unwrap()
+                .contains("Create or join a new one!"));
+        }
+    }
+
+    #[test]
+    fn too_many_games() {
+        let limits = Limits {
+            max_games: 1,
+            ..Limits::default()
+        };
+        let client = Client::untracked(mkrocket_with(GameStorage::memory(), limits)).unwrap();
+        let create = || {
+            client
+                .post("/create-game")
+                .header(ContentType::Form)
+                .body("place=world&nickname=Host")
+                .dispatch()
+        };
+        assert_eq!(create().status(), Status::SeeOther);
+        let mut response = create();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert!(response.body_string().unwrap().contains("too many games"));
+    }
+
+    #[test]
+    fn reap_idle_games() {
+        let limits = Limits {
+            idle_timeout: 60,
+            ..Limits::default()
+        };
+        let client = Client::untracked(mkrocket_with(GameStorage::memory(), limits)).unwrap();
+        let response = client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Host")
+            .dispatch();
+        let host = response.cookies();
+        let db = client.rocket().state::<GuardedGames>().unwrap();
+
+        let now = std::time::Instant::now();
+        reap_games(db, now + std::time::Duration::from_secs(30));
+        let response = client.get("/play-round").cookies(host.clone()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        reap_games(db, now + std::time::Duration::from_secs(61));
+        let response = client.get("/play-round").cookies(host).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let db = db.lock().unwrap();
+        assert!(db.games.is_empty());
+        assert!(db.players.is_empty());
+        assert!(db.sessions.is_empty());
+        assert!(db.join_codes.is_empty());
+    }
+
+    #[test]
+    fn round_times_out() {
+        let client = Client::new(mkrocket()).unwrap();
+        client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Host&round_time=1&results_time=&advance=host")
+            .dispatch();
+        client.post("/start-game").dispatch();
+
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        let mut response = client.get("/play-round").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.body_string().unwrap().contains("Time's up!"));
+    }
+
+    #[test]
+    fn poll_game() {
+        let client = Client::new(mkrocket()).unwrap();
+        client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Host&advance=host")
+            .dispatch();
+        client.post("/start-game").dispatch();
+
+        let mut response = client.get("/game-poller").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let view: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(view["player_states"]["1"], "Guessing");
+        assert_eq!(view["player_names"]["1"], "Host");
+        assert_eq!(view["locations_remaining"], 5);
+        assert!(view["current_location"].is_null());
+
+        client
+            .post("/guess")
+            .header(ContentType::Form)
+            .body("lat=30&lon=-90")
+            .dispatch();
+        let mut response = client.get("/game-poller").dispatch();
+        let view: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(view["player_states"]["1"], "GuessResults");
+        assert_eq!(view["everyone_guessed"], true);
+        assert!(view["current_location"].is_object());
+    }
+
+    #[test]
+    fn game_events() {
+        let client = Client::new(mkrocket()).unwrap();
+        client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Host")
+            .dispatch();
+        let response = client.get("/game-events").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("text", "event-stream"))
+        );
+    }
+
+    #[test]
+    fn too_many_event_streams() {
+        let limits = Limits {
+            max_event_streams: Some(0),
+            ..Limits::default()
+        };
+        let client = Client::new(mkrocket_with(GameStorage::memory(), limits)).unwrap();
+        client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Host")
+            .dispatch();
+        let response = client.get("/game-events").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        // Polling still works
+        let response = client.get("/game-poller").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    fn join_code(client: &Client) -> String {
+        let db = client.rocket().state::<GuardedGames>().unwrap();
+        let db = db.lock().unwrap();
+        db.join_codes.keys().next().unwrap().clone()
+    }
+
+    #[test]
+    fn wait_for_everyone() {
+        let client = Client::untracked(mkrocket()).unwrap();
+        let response = client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Host&advance=vote")
+            .dispatch();
+        let host = response.cookies();
+        let response = client
+            .post("/join-game")
+            .header(ContentType::Form)
+            .body(format!("code={}&nickname=Guest", join_code(&client)))
+            .dispatch();
+        let guest = response.cookies();
+        client.post("/start-game").cookies(host.clone()).dispatch();
+
+        for player in [&host, &guest].iter() {
+            client
+                .post("/guess")
+                .header(ContentType::Form)
+                .cookies((*player).clone())
+                .body("lat=30&lon=-90")
+                .dispatch();
+        }
+
+        // The host is ready first, and waits for the guest
+        let mut response = client
+            .get("/advance-guess")
+            .cookies(host.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body_string().unwrap();
+        assert!(body.contains("id=\"waiting\""));
+        assert!(!body.contains("id=\"continue\""));
+
+        let mut response = client.get("/advance-guess").cookies(guest).dispatch();
+        assert!(response.body_string().unwrap().contains("Location 2 of 5"));
+        let mut response = client.get("/play-round").cookies(host).dispatch();
+        assert!(response.body_string().unwrap().contains("Location 2 of 5"));
+    }
+
+    #[test]
+    fn play_multiplayer_game() {
+        // Untracked, so that each player can bring their own cookies
+        let client = Client::untracked(mkrocket()).unwrap();
+        let response = client
+            .post("/create-game")
+            .header(ContentType::Form)
+            .body("place=world&nickname=Host")
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        let host = response.cookies();
+        let code = join_code(&client);
+
+        let response = client
+            .post("/join-game")
+            .header(ContentType::Form)
+            .body(format!("code={}&nickname=Guest", code.to_lowercase()))
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        let guest = response.cookies();
+
+        let response = client
+            .post("/join-game")
+            .header(ContentType::Form)
+            .body("code=NOPE&nickname=Lost")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.cookies().is_empty());
+
+        // Only the host may start the game
+        let response = client.post("/start-game").cookies(guest.clone()).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client.post("/start-game").cookies(host.clone()).dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+
+        // Nobody else can join once the game is running
+        let response = client
+            .post("/join-game")
+            .header(ContentType::Form)
+            .body(format!("code={}&nickname=Late", code))
+            .dispatch();
+        assert!(response.cookies().is_empty());
+
+        for player in [host, guest] {
+            let response = client
+                .post("/guess")
+                .header(ContentType::Form)
+                .cookies(player)
+                .body("lat=30&lon=-90")
+                .dispatch();
             assert_eq!(response.status(), Status::Ok);
         }
     }
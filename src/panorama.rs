@@ -0,0 +1,143 @@
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::location::Location;
+
+/// A Street View panorama, as resolved by the server. The pano ID is the only
+/// thing the browser gets to see before it makes a guess.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Panorama {
+    pub pano_id: String,
+
+    /// Where the panorama actually is, which may be a ways off from the point we asked about
+    pub location: Location,
+}
+
+#[derive(Debug)]
+pub enum PanoramaError {
+    /// There is no Street View coverage near the location
+    NotFound,
+
+    /// We couldn't talk to the Street View API (or it didn't like what we said)
+    Api(String),
+}
+
+pub trait PanoramaResolverTrait {
+    fn resolve(&self, location: &Location) -> Result<Panorama, PanoramaError>;
+}
+
+/// Search radius around the sampled location, in meters
+const SEARCH_RADIUS: u32 = 100_000;
+
+#[derive(Deserialize)]
+struct MetadataLocation {
+    lat: f64,
+    lng: f64,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    status: String,
+    pano_id: Option<String>,
+    location: Option<MetadataLocation>,
+    error_message: Option<String>,
+}
+
+/// Looks up panoramas using the Street View Static API's metadata endpoint,
+/// which (unlike fetching actual images) is free of charge.
+pub struct GooglePanoramaResolver {
+    api_key: String,
+    agent: ureq::Agent,
+}
+
+impl GooglePanoramaResolver {
+    pub fn new(api_key: &str) -> GooglePanoramaResolver {
+        GooglePanoramaResolver {
+            api_key: api_key.to_string(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+        }
+    }
+
+    fn query(&self, location: &Location, source: &str) -> Result<Panorama, PanoramaError> {
+        let metadata: Metadata = self
+            .agent
+            .get("https://maps.googleapis.com/maps/api/streetview/metadata")
+            .query(
+                "location",
+                &format!("{},{}", location.latitude, location.longitude),
+            )
+            .query("radius", &format!("{}", SEARCH_RADIUS))
+            .query("source", source)
+            .query("key", &self.api_key)
+            .call()
+            .map_err(|e| PanoramaError::Api(e.to_string()))?
+            .into_json()
+            .map_err(|e| PanoramaError::Api(e.to_string()))?;
+        match (
+            metadata.status.as_str(),
+            metadata.pano_id,
+            metadata.location,
+        ) {
+            ("OK", Some(pano_id), Some(pano_location)) => Ok(Panorama {
+                pano_id,
+                location: Location {
+                    latitude: pano_location.lat,
+                    longitude: pano_location.lng,
+                },
+            }),
+            ("ZERO_RESULTS", _, _) | ("NOT_FOUND", _, _) => Err(PanoramaError::NotFound),
+            (status, _, _) => Err(PanoramaError::Api(format!(
+                "{}: {}",
+                status,
+                metadata.error_message.unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+impl PanoramaResolverTrait for GooglePanoramaResolver {
+    fn resolve(&self, location: &Location) -> Result<Panorama, PanoramaError> {
+        // Outdoor panoramas are much more fun, but take what we can get
+        match self.query(location, "outdoor") {
+            Err(PanoramaError::NotFound) => self.query(location, "default"),
+            result => result,
+        }
+    }
+}
+
+pub struct MockPanoramaResolver {}
+
+impl PanoramaResolverTrait for MockPanoramaResolver {
+    fn resolve(&self, location: &Location) -> Result<Panorama, PanoramaError> {
+        Ok(Panorama {
+            pano_id: "mock-pano".to_string(),
+            location: location.clone(),
+        })
+    }
+}
+
+pub enum PanoramaResolver {
+    Google(GooglePanoramaResolver),
+    Mock(MockPanoramaResolver),
+}
+
+impl PanoramaResolver {
+    pub fn mock() -> PanoramaResolver {
+        PanoramaResolver::Mock(MockPanoramaResolver {})
+    }
+
+    pub fn google(api_key: &str) -> PanoramaResolver {
+        PanoramaResolver::Google(GooglePanoramaResolver::new(api_key))
+    }
+}
+
+impl PanoramaResolverTrait for PanoramaResolver {
+    fn resolve(&self, location: &Location) -> Result<Panorama, PanoramaError> {
+        match self {
+            PanoramaResolver::Google(x) => x.resolve(location),
+            PanoramaResolver::Mock(x) => x.resolve(location),
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::location::DistanceKm;
+use crate::settings::ScoringCurve;
+
+pub type Points = usize;
+
+/// The most points a single guess can get for its distance
+const MAX_POINTS: Points = 10_000;
+
+/// How far apart two places on the planet can be, which the curves are tuned for
+const PLANET_EXTENT: DistanceKm = 20_000.0;
+
+/// Stretch distances on smaller maps, so that missing by 100km in a game of Texas costs more
+/// than it would in a game of the world. Only by the square root of how much smaller the
+/// map is, though, since players can't be expected to be that much more precise on it.
+fn scale_distance(distance: DistanceKm, extent: Option<DistanceKm>) -> DistanceKm {
+    match extent {
+        Some(extent) if extent > 0.0 => distance * (PLANET_EXTENT / extent).max(1.0).sqrt(),
+        _ => distance,
+    }
+}
+
+/// Everything about a guess that a scorer might care about
+pub struct ScoredGuess {
+    /// How far off the guess was, scaled to the size of the place
+    pub distance: DistanceKm,
+
+    /// How long the player took to guess
+    pub elapsed: Duration,
+
+    /// How long the player had to guess, if there was a limit
+    pub time_limit: Option<Duration>,
+
+    /// Whether the guess was in the same country as the location, if we know
+    pub right_country: Option<bool>,
+}
+
+impl ScoredGuess {
+    pub fn new(
+        distance: DistanceKm,
+        extent: Option<DistanceKm>,
+        elapsed: Duration,
+        time_limit: Option<Duration>,
+    ) -> ScoredGuess {
+        ScoredGuess {
+            distance: scale_distance(distance, extent),
+            elapsed,
+            time_limit,
+            right_country: None,
+        }
+    }
+}
+
+/// Where a guess's points came from
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct ScoreBreakdown {
+    pub distance_points: Points,
+    pub time_bonus: Points,
+    #[serde(default)]
+    pub country_bonus: Points,
+}
+
+impl ScoreBreakdown {
+    fn distance_only(distance_points: Points) -> ScoreBreakdown {
+        ScoreBreakdown {
+            distance_points,
+            time_bonus: 0,
+            country_bonus: 0,
+        }
+    }
+
+    pub fn total(&self) -> Points {
+        self.distance_points + self.time_bonus + self.country_bonus
+    }
+}
+
+pub trait ScorerTrait {
+    fn score(&self, guess: &ScoredGuess) -> ScoreBreakdown;
+}
+
+/// We want approximately the following place numbers
+/// Maximum score: 10,000pts, <1km
+/// Same city: 5,000pts, <20km
+/// Same state (Texas): 2,500pts, <200km
+/// Same US-sized country (US, China, Australia, Mexico, Europe): 1,000pts, <2,000km
+/// Same planet: 10pts, <10,000km
+fn distance_to_points(distance: DistanceKm) -> Points {
+    if distance < 1.0 {
+        MAX_POINTS
+    } else if distance > 20_000.0 {
+        10
+    } else {
+        (10_000.0 - 3174.471323 * distance.ln().sqrt()) as Points
+    }
+}
+
+/// Our own curve, which rewards getting the right city/state/country
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LogSqrtScorer {}
+
+impl ScorerTrait for LogSqrtScorer {
+    fn score(&self, guess: &ScoredGuess) -> ScoreBreakdown {
+        ScoreBreakdown::distance_only(distance_to_points(guess.distance))
+    }
+}
+
+/// GeoGuessr-style: half the points are gone by ~1,000km, and the rest trail off from there
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExponentialScorer {
+    /// Distance over which the points fall to about a third
+    pub falloff_km: DistanceKm,
+}
+
+impl ScorerTrait for ExponentialScorer {
+    fn score(&self, guess: &ScoredGuess) -> ScoreBreakdown {
+        let points = if guess.distance < 1.0 {
+            MAX_POINTS
+        } else {
+            (MAX_POINTS as f64 * (-guess.distance / self.falloff_km).exp()) as Points
+        };
+        ScoreBreakdown::distance_only(points)
+    }
+}
+
+/// Points fall off in a straight line, down to nothing at the given distance
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LinearScorer {
+    pub zero_at_km: DistanceKm,
+}
+
+impl ScorerTrait for LinearScorer {
+    fn score(&self, guess: &ScoredGuess) -> ScoreBreakdown {
+        let fraction = (1.0 - guess.distance / self.zero_at_km).max(0.0);
+        ScoreBreakdown::distance_only((MAX_POINTS as f64 * fraction) as Points)
+    }
+}
+
+/// Hit or miss: everything for being close enough, nothing otherwise
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ThresholdScorer {
+    pub radius_km: DistanceKm,
+}
+
+impl ScorerTrait for ThresholdScorer {
+    fn score(&self, guess: &ScoredGuess) -> ScoreBreakdown {
+        let points = if guess.distance <= self.radius_km {
+            MAX_POINTS
+        } else {
+            0
+        };
+        ScoreBreakdown::distance_only(points)
+    }
+}
+
+/// The standard curve, plus a bonus for guessing quickly. The bonus is scaled by how good
+/// the guess was, so that clicking anywhere straight away doesn't pay.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TimeBonusScorer {
+    pub max_bonus: Points,
+
+    /// How long the bonus lasts, when the round has no time limit
+    pub window_secs: u64,
+}
+
+impl ScorerTrait for TimeBonusScorer {
+    fn score(&self, guess: &ScoredGuess) -> ScoreBreakdown {
+        let distance_points = distance_to_points(guess.distance);
+        let window = guess
+            .time_limit
+            .unwrap_or_else(|| Duration::from_secs(self.window_secs));
+        let time_left = if window.as_secs_f64() > 0.0 {
+            (1.0 - guess.elapsed.as_secs_f64() / window.as_secs_f64()).max(0.0)
+        } else {
+            0.0
+        };
+        let accuracy = distance_points as f64 / MAX_POINTS as f64;
+        ScoreBreakdown {
+            distance_points,
+            time_bonus: (self.max_bonus as f64 * accuracy * time_left) as Points,
+            country_bonus: 0,
+        }
+    }
+}
+
+/// The standard curve, plus a bonus for getting the country right, however far off the
+/// guess was. Places without countries in their dataset never get the bonus.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CountryBonusScorer {
+    pub bonus: Points,
+}
+
+impl ScorerTrait for CountryBonusScorer {
+    fn score(&self, guess: &ScoredGuess) -> ScoreBreakdown {
+        ScoreBreakdown {
+            country_bonus: match guess.right_country {
+                Some(true) => self.bonus,
+                _ => 0,
+            },
+            ..ScoreBreakdown::distance_only(distance_to_points(guess.distance))
+        }
+    }
+}
+
+/// How a game turns guesses into points
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Scorer {
+    LogSqrt(LogSqrtScorer),
+    Exponential(ExponentialScorer),
+    Linear(LinearScorer),
+    Threshold(ThresholdScorer),
+    TimeBonus(TimeBonusScorer),
+    CountryBonus(CountryBonusScorer),
+}
+
+impl Default for Scorer {
+    fn default() -> Scorer {
+        Scorer::from(ScoringCurve::Standard)
+    }
+}
+
+impl From<ScoringCurve> for Scorer {
+    fn from(curve: ScoringCurve) -> Scorer {
+        match curve {
+            ScoringCurve::Standard => Scorer::LogSqrt(LogSqrtScorer {}),
+            ScoringCurve::Exponential => {
+                Scorer::Exponential(ExponentialScorer { falloff_km: 1492.7 })
+            }
+            ScoringCurve::Linear => Scorer::Linear(LinearScorer {
+                zero_at_km: PLANET_EXTENT / 2.0,
+            }),
+            ScoringCurve::Threshold => Scorer::Threshold(ThresholdScorer { radius_km: 500.0 }),
+            ScoringCurve::TimeBonus => Scorer::TimeBonus(TimeBonusScorer {
+                max_bonus: 2_000,
+                window_secs: 60,
+            }),
+            ScoringCurve::CountryBonus => Scorer::CountryBonus(CountryBonusScorer { bonus: 2_000 }),
+        }
+    }
+}
+
+impl ScorerTrait for Scorer {
+    fn score(&self, guess: &ScoredGuess) -> ScoreBreakdown {
+        match self {
+            Scorer::LogSqrt(x) => x.score(guess),
+            Scorer::Exponential(x) => x.score(guess),
+            Scorer::Linear(x) => x.score(guess),
+            Scorer::Threshold(x) => x.score(guess),
+            Scorer::TimeBonus(x) => x.score(guess),
+            Scorer::CountryBonus(x) => x.score(guess),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn score(curve: ScoringCurve, distance: DistanceKm, extent: Option<DistanceKm>) -> Points {
+        let guess = ScoredGuess::new(distance, extent, Duration::from_secs(30), None);
+        Scorer::from(curve).score(&guess).total()
+    }
+
+    #[test]
+    fn test_scoring_curves() {
+        assert_eq!(score(ScoringCurve::Standard, 0.5, None), 10_000);
+        assert_eq!(score(ScoringCurve::Exponential, 0.5, None), 10_000);
+        // The exponential curve is kinder to close guesses, but harsher on far away ones
+        assert!(
+            score(ScoringCurve::Exponential, 20.0, None)
+                > score(ScoringCurve::Standard, 20.0, None)
+        );
+        assert!(
+            score(ScoringCurve::Exponential, 5000.0, None)
+                < score(ScoringCurve::Standard, 5000.0, None)
+        );
+        assert_eq!(score(ScoringCurve::Exponential, 20_000.0, None), 0);
+
+        assert_eq!(score(ScoringCurve::Linear, 0.0, None), 10_000);
+        assert_eq!(score(ScoringCurve::Linear, 5_000.0, None), 5_000);
+        assert_eq!(score(ScoringCurve::Linear, 15_000.0, None), 0);
+
+        assert_eq!(score(ScoringCurve::Threshold, 499.0, None), 10_000);
+        assert_eq!(score(ScoringCurve::Threshold, 501.0, None), 0);
+
+        // Missing by 100km in Texas is a lot worse than missing by 100km anywhere on the planet
+        let texas = Some(1_250.0);
+        assert_eq!(score(ScoringCurve::Standard, 0.05, texas), 10_000);
+        assert!(score(ScoringCurve::Standard, 100.0, texas) < 2_500);
+        assert!(score(ScoringCurve::Standard, 100.0, None) > 3_000);
+        assert_eq!(
+            score(ScoringCurve::Standard, 100.0, Some(40_000.0)),
+            score(ScoringCurve::Standard, 100.0, None)
+        );
+        assert_eq!(score(ScoringCurve::Threshold, 150.0, texas), 0);
+        assert_eq!(score(ScoringCurve::Threshold, 150.0, None), 10_000);
+    }
+
+    #[test]
+    fn test_good_guesses() {
+        // Being about as close as a good player would be is worth about as much anywhere
+        let places = [
+            ("a city", 30.0, 2.0),
+            ("Texas", 1_250.0, 50.0),
+            ("the US", 4_500.0, 200.0),
+            ("the world", PLANET_EXTENT, 500.0),
+        ];
+        for (place, extent, distance) in places.iter() {
+            let points = score(ScoringCurve::Standard, *distance, Some(*extent));
+            assert!((2_000..4_000).contains(&points), "{}: {}", place, points);
+        }
+    }
+
+    #[test]
+    fn test_time_bonus() {
+        let scorer = Scorer::from(ScoringCurve::TimeBonus);
+        let secs = Duration::from_secs;
+
+        // Half the time gone, on a perfect guess
+        let score = scorer.score(&ScoredGuess::new(0.5, None, secs(30), None));
+        assert_eq!(
+            score,
+            ScoreBreakdown {
+                distance_points: 10_000,
+                time_bonus: 1_000,
+                country_bonus: 0,
+            }
+        );
+        assert_eq!(score.total(), 11_000);
+
+        // The round's own time limit is used when there is one
+        let score = scorer.score(&ScoredGuess::new(0.5, None, secs(30), Some(secs(120))));
+        assert_eq!(score.time_bonus, 1_500);
+
+        // Too slow for a bonus
+        let score = scorer.score(&ScoredGuess::new(0.5, None, secs(90), None));
+        assert_eq!(score.time_bonus, 0);
+
+        // Far away guesses don't get much of a bonus, no matter how quick
+        let score = scorer.score(&ScoredGuess::new(10_000.0, None, secs(0), None));
+        assert!(score.time_bonus < 200);
+        assert_eq!(score.distance_points, score.total() - score.time_bonus);
+    }
+
+    #[test]
+    fn test_country_bonus() {
+        let scorer = Scorer::from(ScoringCurve::CountryBonus);
+        let mut guess = ScoredGuess::new(300.0, None, Duration::from_secs(10), None);
+        let unknown = scorer.score(&guess);
+        assert_eq!(unknown.country_bonus, 0);
+        assert_eq!(unknown.total(), score(ScoringCurve::Standard, 300.0, None));
+
+        guess.right_country = Some(true);
+        let right = scorer.score(&guess);
+        assert_eq!(right.country_bonus, 2_000);
+        assert_eq!(right.total(), unknown.total() + 2_000);
+
+        guess.right_country = Some(false);
+        assert_eq!(scorer.score(&guess), unknown);
+        // Only the country bonus curve cares
+        guess.right_country = Some(true);
+        assert_eq!(Scorer::from(ScoringCurve::Standard).score(&guess), unknown);
+    }
+}
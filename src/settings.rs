@@ -0,0 +1,323 @@
+use crate::daily::Day;
+use crate::location::DistanceKm;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const MAX_ROUNDS: usize = 20;
+
+/// Generous, but nobody should be staring at one location for more than an hour
+pub const MAX_TIME_LIMIT_SECS: u64 = 60 * 60;
+
+/// How guesses get turned into points
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScoringCurve {
+    /// Our own curve, which rewards getting the right city/state/country
+    Standard,
+
+    /// Points fall off exponentially with distance, like GeoGuessr
+    Exponential,
+
+    /// Points fall off evenly with distance
+    Linear,
+
+    /// All or nothing, depending on whether the guess is close enough
+    Threshold,
+
+    /// The standard curve, plus a bonus for guessing quickly
+    TimeBonus,
+
+    /// The standard curve, plus a bonus for guessing the right country
+    CountryBonus,
+}
+
+impl FromStr for ScoringCurve {
+    type Err = SettingsError;
+
+    fn from_str(s: &str) -> Result<ScoringCurve, SettingsError> {
+        match s {
+            "standard" => Ok(ScoringCurve::Standard),
+            "exponential" => Ok(ScoringCurve::Exponential),
+            "linear" => Ok(ScoringCurve::Linear),
+            "threshold" => Ok(ScoringCurve::Threshold),
+            "time-bonus" => Ok(ScoringCurve::TimeBonus),
+            "country-bonus" => Ok(ScoringCurve::CountryBonus),
+            _ => Err(SettingsError::UnknownScoring(s.to_string())),
+        }
+    }
+}
+
+/// What the players are allowed to do in Street View
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum MovementRule {
+    /// Anything goes
+    Moving,
+
+    /// Look around, but stay put
+    NoMove,
+
+    /// No moving, no looking around, no zooming. What you see is what you get.
+    NoPanZoom,
+}
+
+impl FromStr for MovementRule {
+    type Err = SettingsError;
+
+    fn from_str(s: &str) -> Result<MovementRule, SettingsError> {
+        match s {
+            "moving" => Ok(MovementRule::Moving),
+            "no-move" => Ok(MovementRule::NoMove),
+            "no-pan-zoom" => Ok(MovementRule::NoPanZoom),
+            _ => Err(SettingsError::UnknownMovement(s.to_string())),
+        }
+    }
+}
+
+/// When everybody moves on from looking at the results to the next location
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AdvancePolicy {
+    /// As soon as everyone has guessed, or once the results time limit runs out if there
+    /// is one
+    #[default]
+    Automatic,
+
+    /// Once everyone has guessed, when the host says so
+    Host,
+
+    /// As soon as more than half of the players are ready, even if the rest haven't guessed
+    Vote,
+
+    /// Only when the results time limit runs out
+    Timer,
+}
+
+impl FromStr for AdvancePolicy {
+    type Err = SettingsError;
+
+    fn from_str(s: &str) -> Result<AdvancePolicy, SettingsError> {
+        match s {
+            "automatic" => Ok(AdvancePolicy::Automatic),
+            "host" => Ok(AdvancePolicy::Host),
+            "vote" => Ok(AdvancePolicy::Vote),
+            "timer" => Ok(AdvancePolicy::Timer),
+            _ => Err(SettingsError::UnknownAdvance(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SettingsError {
+    UnknownPlace(String),
+    BadNumberOfRounds(usize),
+    TimeLimitTooLong(u64),
+    UnknownScoring(String),
+    UnknownMovement(String),
+    UnknownAdvance(String),
+    BadSpacing(DistanceKm),
+
+    /// The place isn't big enough to fit the locations that far apart
+    SpacingTooBig(DistanceKm),
+
+    /// Moving on by timer needs a timer to go by
+    TimerWithoutTimeLimit,
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsError::UnknownPlace(place) => {
+                write!(f, "There is no place called {:?}.", place)
+            }
+            SettingsError::BadNumberOfRounds(rounds) => write!(
+                f,
+                "A game must have between 1 and {} rounds, not {}.",
+                MAX_ROUNDS, rounds
+            ),
+            SettingsError::TimeLimitTooLong(secs) => write!(
+                f,
+                "Time limits can be at most {} seconds, not {}.",
+                MAX_TIME_LIMIT_SECS, secs
+            ),
+            SettingsError::UnknownScoring(scoring) => {
+                write!(f, "There is no scoring curve called {:?}.", scoring)
+            }
+            SettingsError::UnknownMovement(movement) => {
+                write!(f, "There is no movement rule called {:?}.", movement)
+            }
+            SettingsError::UnknownAdvance(advance) => {
+                write!(f, "There is no way of moving on called {:?}.", advance)
+            }
+            SettingsError::BadSpacing(km) => write!(
+                f,
+                "The spacing between locations must be a distance in km, not {}.",
+                km
+            ),
+            SettingsError::SpacingTooBig(max) => write!(
+                f,
+                "The locations in that place can't be spaced more than {:.0}km apart.",
+                max
+            ),
+            SettingsError::TimerWithoutTimeLimit => write!(
+                f,
+                "To move on by timer, there needs to be a time limit on the results."
+            ),
+        }
+    }
+}
+
+/// Everything the host gets to choose about a game
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GameSettings {
+    pub num_rounds: usize,
+
+    /// Seconds per location, None for no limit
+    pub round_time_limit: Option<u64>,
+
+    /// Seconds to show the results of each location before moving on, None to wait for the players
+    pub results_time_limit: Option<u64>,
+
+    pub scoring: ScoringCurve,
+    pub movement: MovementRule,
+
+    #[serde(default)]
+    pub advance: AdvancePolicy,
+
+    /// Key of the place (from the config) to draw locations from
+    pub dataset: String,
+
+    /// How far apart locations in the place can be, which scoring is scaled to.
+    /// None to score as if the whole planet was in play.
+    #[serde(default)]
+    pub scoring_extent: Option<DistanceKm>,
+
+    /// How far apart (in km) every location in the game has to be. 0 only rules out
+    /// the same location coming up twice.
+    #[serde(default)]
+    pub min_spacing_km: DistanceKm,
+
+    /// Where the locations come from: the same seed, place and number of rounds always
+    /// gives the same locations. None to pick one when the game is created.
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    /// Which day's daily challenge this is, if it is one
+    #[serde(default)]
+    pub daily: Option<Day>,
+}
+
+impl GameSettings {
+    /// The settings games had before they were configurable
+    pub fn new(dataset: &str) -> GameSettings {
+        GameSettings {
+            num_rounds: 5,
+            round_time_limit: None,
+            results_time_limit: None,
+            scoring: ScoringCurve::Standard,
+            movement: MovementRule::Moving,
+            advance: AdvancePolicy::Automatic,
+            dataset: dataset.to_string(),
+            scoring_extent: None,
+            min_spacing_km: 0.0,
+            seed: None,
+            daily: None,
+        }
+    }
+
+    /// Check that the settings make sense, given the places that are available
+    pub fn validate<'a>(
+        &self,
+        mut places: impl Iterator<Item = &'a str>,
+    ) -> Result<(), SettingsError> {
+        if !places.any(|place| place == self.dataset) {
+            return Err(SettingsError::UnknownPlace(self.dataset.clone()));
+        }
+        if self.num_rounds == 0 || self.num_rounds > MAX_ROUNDS {
+            return Err(SettingsError::BadNumberOfRounds(self.num_rounds));
+        }
+        for limit in [self.round_time_limit, self.results_time_limit].iter() {
+            match limit {
+                Some(secs) if *secs > MAX_TIME_LIMIT_SECS => {
+                    return Err(SettingsError::TimeLimitTooLong(*secs));
+                }
+                _ => {}
+            }
+        }
+        if !(self.min_spacing_km >= 0.0 && self.min_spacing_km.is_finite()) {
+            return Err(SettingsError::BadSpacing(self.min_spacing_km));
+        }
+        if self.advance == AdvancePolicy::Timer && self.results_time_limit.is_none() {
+            return Err(SettingsError::TimerWithoutTimeLimit);
+        }
+        Ok(())
+    }
+
+    pub fn round_duration(&self) -> Option<Duration> {
+        self.round_time_limit.map(Duration::from_secs)
+    }
+
+    pub fn results_duration(&self) -> Option<Duration> {
+        self.results_time_limit.map(Duration::from_secs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let places = || vec!["world", "texas"].into_iter();
+        assert_eq!(GameSettings::new("texas").validate(places()), Ok(()));
+        assert_eq!(
+            GameSettings::new("mars").validate(places()),
+            Err(SettingsError::UnknownPlace("mars".to_string()))
+        );
+
+        let mut settings = GameSettings::new("world");
+        settings.num_rounds = 0;
+        assert_eq!(
+            settings.validate(places()),
+            Err(SettingsError::BadNumberOfRounds(0))
+        );
+
+        let mut settings = GameSettings::new("world");
+        settings.results_time_limit = Some(MAX_TIME_LIMIT_SECS + 1);
+        assert_eq!(
+            settings.validate(places()),
+            Err(SettingsError::TimeLimitTooLong(MAX_TIME_LIMIT_SECS + 1))
+        );
+
+        let mut settings = GameSettings::new("world");
+        settings.advance = AdvancePolicy::Timer;
+        assert_eq!(
+            settings.validate(places()),
+            Err(SettingsError::TimerWithoutTimeLimit)
+        );
+        settings.results_time_limit = Some(10);
+        assert_eq!(settings.validate(places()), Ok(()));
+
+        let mut settings = GameSettings::new("world");
+        settings.min_spacing_km = -1.0;
+        assert_eq!(
+            settings.validate(places()),
+            Err(SettingsError::BadSpacing(-1.0))
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("no-pan-zoom".parse(), Ok(MovementRule::NoPanZoom));
+        assert_eq!("exponential".parse(), Ok(ScoringCurve::Exponential));
+        assert_eq!("time-bonus".parse(), Ok(ScoringCurve::TimeBonus));
+        assert_eq!("country-bonus".parse(), Ok(ScoringCurve::CountryBonus));
+        assert_eq!("vote".parse(), Ok(AdvancePolicy::Vote));
+        assert_eq!(
+            "teleport".parse::<MovementRule>(),
+            Err(SettingsError::UnknownMovement("teleport".to_string()))
+        );
+    }
+}
@@ -0,0 +1,323 @@
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::daily::Leaderboard;
+use crate::game_logic::Game;
+use crate::GameId;
+
+/// Where games get saved, so that they survive a restart. Games are handed over
+/// already serialized, so that nobody has to hold a game's lock while it's being written.
+///
+/// Only games are stored: which player is in which game can be worked out from the games.
+pub trait GameStorageTrait {
+    /// Everything which has been saved, to pick up where we left off
+    fn load(&self) -> Vec<(GameId, Game)>;
+
+    fn save_game(&mut self, id: GameId, serialized: String);
+
+    /// Forget about a game for good
+    fn delete_game(&mut self, id: GameId);
+
+    /// The daily challenge leaderboard, as it was last saved
+    fn load_leaderboard(&self) -> Option<Leaderboard>;
+
+    fn save_leaderboard(&mut self, serialized: String);
+}
+
+/// Keeps saved games around only for as long as the server is running
+#[derive(Default)]
+pub struct MemoryGameStorage {
+    games: HashMap<GameId, String>,
+    leaderboard: Option<String>,
+}
+
+impl MemoryGameStorage {
+    pub fn new() -> MemoryGameStorage {
+        MemoryGameStorage {
+            games: HashMap::new(),
+            leaderboard: None,
+        }
+    }
+}
+
+impl GameStorageTrait for MemoryGameStorage {
+    fn load(&self) -> Vec<(GameId, Game)> {
+        self.games
+            .iter()
+            .filter_map(|(id, serialized)| Some((*id, serde_json::from_str(serialized).ok()?)))
+            .collect()
+    }
+
+    fn save_game(&mut self, id: GameId, serialized: String) {
+        self.games.insert(id, serialized);
+    }
+
+    fn delete_game(&mut self, id: GameId) {
+        self.games.remove(&id);
+    }
+
+    fn load_leaderboard(&self) -> Option<Leaderboard> {
+        serde_json::from_str(self.leaderboard.as_ref()?).ok()
+    }
+
+    fn save_leaderboard(&mut self, serialized: String) {
+        self.leaderboard = Some(serialized);
+    }
+}
+
+/// Saves each game as a JSON file in a directory
+pub struct FileGameStorage {
+    directory: PathBuf,
+}
+
+impl FileGameStorage {
+    pub fn new(directory: &str) -> std::io::Result<FileGameStorage> {
+        std::fs::create_dir_all(directory)?;
+        Ok(FileGameStorage {
+            directory: PathBuf::from(directory),
+        })
+    }
+
+    fn game_path(&self, id: GameId) -> PathBuf {
+        self.directory.join(format!("game-{}.json", id))
+    }
+
+    fn leaderboard_path(&self) -> PathBuf {
+        self.directory.join("leaderboard.json")
+    }
+
+    /// Write then rename, so a crash halfway through doesn't leave a corrupt file behind
+    fn write(path: &Path, serialized: String) {
+        let tmp_path = path.with_extension("json.tmp");
+        let result =
+            std::fs::write(&tmp_path, serialized).and_then(|_| std::fs::rename(&tmp_path, path));
+        if let Err(e) = result {
+            log::error!("Could not save {}: {}", path.display(), e);
+        }
+    }
+
+    fn load_game(path: &Path) -> Option<(GameId, Game)> {
+        let id = path
+            .file_stem()?
+            .to_str()?
+            .strip_prefix("game-")?
+            .parse()
+            .ok()?;
+        let contents = std::fs::read_to_string(path).ok()?;
+        match serde_json::from_str(&contents) {
+            Ok(game) => Some((id, game)),
+            Err(e) => {
+                log::error!("Could not load game from {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+impl GameStorageTrait for FileGameStorage {
+    fn load(&self) -> Vec<(GameId, Game)> {
+        let entries = match std::fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!(
+                    "Could not read saved games from {}: {}",
+                    self.directory.display(),
+                    e
+                );
+                return vec![];
+            }
+        };
+        entries
+            .filter_map(|entry| Self::load_game(&entry.ok()?.path()))
+            .collect()
+    }
+
+    fn save_game(&mut self, id: GameId, serialized: String) {
+        Self::write(&self.game_path(id), serialized);
+    }
+
+    fn delete_game(&mut self, id: GameId) {
+        let path = self.game_path(id);
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            // It was never saved in the first place
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::error!("Could not delete game {}: {}", path.display(), e),
+        }
+    }
+
+    fn load_leaderboard(&self) -> Option<Leaderboard> {
+        let path = self.leaderboard_path();
+        let contents = std::fs::read_to_string(&path).ok()?;
+        match serde_json::from_str(&contents) {
+            Ok(leaderboard) => Some(leaderboard),
+            Err(e) => {
+                log::error!("Could not load leaderboard from {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn save_leaderboard(&mut self, serialized: String) {
+        Self::write(&self.leaderboard_path(), serialized);
+    }
+}
+
+pub enum GameStorage {
+    Memory(MemoryGameStorage),
+    File(FileGameStorage),
+}
+
+impl GameStorage {
+    pub fn memory() -> GameStorage {
+        GameStorage::Memory(MemoryGameStorage::new())
+    }
+
+    pub fn file(directory: &str) -> std::io::Result<GameStorage> {
+        Ok(GameStorage::File(FileGameStorage::new(directory)?))
+    }
+}
+
+impl GameStorageTrait for GameStorage {
+    fn load(&self) -> Vec<(GameId, Game)> {
+        match self {
+            GameStorage::Memory(x) => x.load(),
+            GameStorage::File(x) => x.load(),
+        }
+    }
+
+    fn save_game(&mut self, id: GameId, serialized: String) {
+        match self {
+            GameStorage::Memory(x) => x.save_game(id, serialized),
+            GameStorage::File(x) => x.save_game(id, serialized),
+        }
+    }
+
+    fn delete_game(&mut self, id: GameId) {
+        match self {
+            GameStorage::Memory(x) => x.delete_game(id),
+            GameStorage::File(x) => x.delete_game(id),
+        }
+    }
+
+    fn load_leaderboard(&self) -> Option<Leaderboard> {
+        match self {
+            GameStorage::Memory(x) => x.load_leaderboard(),
+            GameStorage::File(x) => x.load_leaderboard(),
+        }
+    }
+
+    fn save_leaderboard(&mut self, serialized: String) {
+        match self {
+            GameStorage::Memory(x) => x.save_leaderboard(serialized),
+            GameStorage::File(x) => x.save_leaderboard(serialized),
+        }
+    }
+}
+
+/// How games should be stored, as given in the config file
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Memory,
+    File { directory: String },
+}
+
+impl StorageConfig {
+    pub fn build(&self) -> std::io::Result<GameStorage> {
+        match self {
+            StorageConfig::Memory => Ok(GameStorage::memory()),
+            StorageConfig::File { directory } => GameStorage::file(directory),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::daily::{DailyScore, Day};
+    use crate::location::Location;
+    use crate::location_generator::LocationGenerator;
+    use crate::settings::{GameSettings, ScoringCurve};
+
+    fn save(storage: &mut GameStorage, id: GameId, game: &Game) {
+        storage.save_game(id, serde_json::to_string(game).unwrap());
+    }
+
+    fn check_round_trip(mut storage: GameStorage) {
+        let mut game = Game::new(GameSettings::new("world"), &LocationGenerator::mock()).unwrap();
+        game.add_player(7, "Saved").unwrap();
+        game.set_join_code("ABCDE");
+        save(&mut storage, 3, &game);
+        game.start().unwrap();
+        save(&mut storage, 3, &game);
+
+        let games = storage.load();
+        assert_eq!(games.len(), 1);
+        let (id, loaded) = &games[0];
+        assert_eq!(*id, 3);
+        assert_eq!(loaded.get_join_code(), "ABCDE");
+        assert_eq!(loaded.get_player(7).unwrap().name, "Saved");
+        assert_eq!(loaded.get_locations_remaining(), 5);
+        assert!(loaded.is_host(7));
+
+        // Games from before there were scorers score the way their settings say
+        let mut settings = GameSettings::new("world");
+        settings.scoring = ScoringCurve::Threshold;
+        let mut game = Game::new(settings, &LocationGenerator::mock()).unwrap();
+        game.add_player(8, "Old").unwrap();
+        game.start().unwrap();
+        let mut saved = serde_json::to_value(&game).unwrap();
+        saved.as_object_mut().unwrap().remove("scorer");
+        storage.save_game(4, saved.to_string());
+        let (_, mut old) = storage.load().into_iter().find(|(id, _)| *id == 4).unwrap();
+        old.resume();
+        let far_away = Location {
+            latitude: -30.0,
+            longitude: -82.0,
+        };
+        let result = old.guess(8, &far_away, &LocationGenerator::mock()).unwrap();
+        assert_eq!(result.points_gained, 0);
+
+        storage.delete_game(3);
+        storage.delete_game(4);
+        assert!(storage.load().is_empty());
+        save(&mut storage, 3, &game);
+
+        assert!(storage.load_leaderboard().is_none());
+        let mut leaderboard = Leaderboard::default();
+        let score = DailyScore {
+            name: "Saved".to_string(),
+            score: 1234,
+            game: 3,
+        };
+        leaderboard.record("world", Day::today(), score.clone());
+        storage.save_leaderboard(serde_json::to_string(&leaderboard).unwrap());
+        assert_eq!(
+            storage
+                .load_leaderboard()
+                .unwrap()
+                .scores("world", Day::today()),
+            &[score]
+        );
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        check_round_trip(GameStorage::memory());
+    }
+
+    #[test]
+    fn test_file_storage() {
+        let directory = crate::datafile::test::TempDir::new("storage");
+        let path = directory.path().to_str().unwrap();
+        check_round_trip(GameStorage::file(path).unwrap());
+
+        // Anything else in there is ignored
+        std::fs::write(directory.file("notes.txt"), "hello").unwrap();
+        std::fs::write(directory.file("game-9.json"), "{ corrupt").unwrap();
+        let storage = GameStorage::file(path).unwrap();
+        assert_eq!(storage.load().len(), 1);
+    }
+}